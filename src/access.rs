@@ -0,0 +1,211 @@
+//! Which peers may connect to this node, from the lists persisted in the data
+//! directory and the command-line options, and which shared files each peer
+//! may see and download.
+
+use crate::cli::Cli;
+use crate::network;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the peer lists inside the data directory
+const ACCESS_FILE: &str = "access.json";
+
+/// Peers the user has chosen to trust or block
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PeerLists {
+    /// Peers allowed to connect in private mode
+    #[serde(default)]
+    pub trusted: BTreeSet<PeerId>,
+    /// Peers never allowed to connect
+    #[serde(default)]
+    pub blocked: BTreeSet<PeerId>,
+    /// Named groups of peers that access rules can refer to
+    #[serde(default)]
+    pub groups: BTreeMap<String, BTreeSet<PeerId>>,
+}
+
+impl PeerLists {
+    /// Loads the lists from `data_dir`, returning empty lists if none were saved yet.
+    pub fn load(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = access_path(data_dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let bytes = fs::read(&path)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Writes the lists to `data_dir`.
+    pub fn save(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(data_dir)?;
+        let json = serde_json::to_vec_pretty(self)?;
+        fs::write(access_path(data_dir), json)?;
+        Ok(())
+    }
+}
+
+/// The peers a node accepts connections from and makes connections to
+#[derive(Debug)]
+pub struct PeerAccess {
+    /// Whether only trusted peers are allowed
+    pub private: bool,
+    /// Trusted peers, including the bootstrap, static and relay peers given on the command line
+    pub trusted: HashSet<PeerId>,
+    /// Peers never allowed to connect
+    pub blocked: HashSet<PeerId>,
+    /// Members of each named group
+    pub groups: HashMap<String, HashSet<PeerId>>,
+}
+
+impl PeerAccess {
+    /// Combines the lists saved in `data_dir` with the command-line options.
+    ///
+    /// Peers given with `--bootstrap`, `--static-peer` or `--relay` are
+    /// trusted, since the user asked to connect to them.
+    pub fn new(cli: &Cli, data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let lists = PeerLists::load(data_dir)?;
+        let mut trusted: HashSet<PeerId> = lists.trusted.into_iter().collect();
+        for addr in cli.bootstrap_addrs.iter().chain(&cli.static_peers).chain(&cli.relays) {
+            trusted.insert(network::peer_id_of(addr)?);
+        }
+        Ok(PeerAccess {
+            private: cli.private,
+            trusted,
+            blocked: lists.blocked.into_iter().collect(),
+            groups: lists
+                .groups
+                .into_iter()
+                .map(|(name, members)| (name, members.into_iter().collect()))
+                .collect(),
+        })
+    }
+
+    /// Whether `peer` may connect; in private mode only trusted peers may.
+    pub fn allows(&self, peer: &PeerId) -> bool {
+        !self.blocked.contains(peer) && (!self.private || self.trusted.contains(peer))
+    }
+
+    /// Whether `rule` lets `peer` see and download a file.
+    pub fn permits(&self, rule: &AccessRule, peer: &PeerId) -> bool {
+        match rule {
+            AccessRule::Public => true,
+            AccessRule::Restricted { peers, groups } => {
+                peers.contains(peer)
+                    || groups
+                        .iter()
+                        .any(|group| self.groups.get(group).is_some_and(|members| members.contains(peer)))
+            }
+        }
+    }
+}
+
+/// Who may see and download a shared file or the files in a shared folder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessRule {
+    /// Every peer that can connect
+    Public,
+    /// Only the listed peers and the members of the listed groups
+    Restricted {
+        #[serde(default)]
+        peers: BTreeSet<PeerId>,
+        #[serde(default)]
+        groups: BTreeSet<String>,
+    },
+}
+
+impl fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessRule::Public => write!(f, "public"),
+            AccessRule::Restricted { peers, groups } => {
+                let peers: Vec<String> = peers.iter().map(ToString::to_string).collect();
+                let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+                write!(f, "peers: [{}] groups: [{}]", peers.join(", "), groups.join(", "))
+            }
+        }
+    }
+}
+
+/// Access rules of shared paths; a path without a rule of its own follows
+/// the rule of its closest ancestor that has one, and is public if none does
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AccessRules(BTreeMap<PathBuf, AccessRule>);
+
+impl AccessRules {
+    /// Sets the rule of `path` and everything below it without a rule of its own.
+    pub fn set(&mut self, path: PathBuf, rule: AccessRule) {
+        self.0.insert(path, rule);
+    }
+
+    /// The rule that applies to `path`.
+    pub fn rule_for(&self, path: &Path) -> &AccessRule {
+        path.ancestors()
+            .find_map(|ancestor| self.0.get(ancestor))
+            .unwrap_or(&AccessRule::Public)
+    }
+
+    /// Iterates over the paths that have a rule of their own.
+    pub fn iter(&self) -> impl Iterator<Item = (&PathBuf, &AccessRule)> {
+        self.0.iter()
+    }
+}
+
+/// Location of the peer lists inside `data_dir`.
+fn access_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(ACCESS_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restricted(peers: &[PeerId], groups: &[&str]) -> AccessRule {
+        AccessRule::Restricted {
+            peers: peers.iter().copied().collect(),
+            groups: groups.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn rule_for_follows_the_closest_ancestor_with_a_rule() {
+        let friend = PeerId::random();
+        let mut rules = AccessRules::default();
+        rules.set("/shares/music".into(), restricted(&[friend], &[]));
+        rules.set("/shares/music/live".into(), restricted(&[], &["band"]));
+        rules.set("/shares/music/live/public".into(), AccessRule::Public);
+
+        assert_eq!(rules.rule_for(Path::new("/shares/notes.txt")), &AccessRule::Public);
+        assert_eq!(rules.rule_for(Path::new("/shares/music")), &restricted(&[friend], &[]));
+        assert_eq!(rules.rule_for(Path::new("/shares/music/a/b.mp3")), &restricted(&[friend], &[]));
+        assert_eq!(rules.rule_for(Path::new("/shares/music/live/c.mp3")), &restricted(&[], &["band"]));
+        assert_eq!(rules.rule_for(Path::new("/shares/music/live/public/d.mp3")), &AccessRule::Public);
+        // Prefixes match whole path components only
+        assert_eq!(rules.rule_for(Path::new("/shares/musical/e.mp3")), &AccessRule::Public);
+    }
+
+    #[test]
+    fn permits_listed_peers_and_group_members() {
+        let (friend, member, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let access = PeerAccess {
+            private: false,
+            trusted: HashSet::new(),
+            blocked: HashSet::new(),
+            groups: HashMap::from([("band".to_string(), HashSet::from([member]))]),
+        };
+
+        assert!(access.permits(&AccessRule::Public, &stranger));
+        let rule = restricted(&[friend], &["band", "unknown"]);
+        assert!(access.permits(&rule, &friend));
+        assert!(access.permits(&rule, &member));
+        assert!(!access.permits(&rule, &stranger));
+        assert!(!access.permits(&restricted(&[friend], &[]), &member));
+        assert!(!access.permits(&restricted(&[], &[]), &friend));
+    }
+}
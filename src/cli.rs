@@ -0,0 +1,283 @@
+//! Command-line interface definitions.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::manifest::ContentHash;
+use libp2p::{Multiaddr, PeerId};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Default identify protocol version advertised to other peers
+pub const DEFAULT_PROTOCOL_VERSION: &str = "rust-p2p-example/1.0.0";
+
+/// Command-line options for the node
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Directory holding the node's persistent state
+    #[arg(long, global = true, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Path of the identity key file (defaults to `<data-dir>/identity.key`)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Address to listen on; can be given multiple times
+    #[arg(
+        long = "listen",
+        global = true,
+        value_name = "MULTIADDR",
+        default_values = ["/ip4/0.0.0.0/tcp/0", "/ip4/0.0.0.0/udp/0/quic-v1"]
+    )]
+    pub listen_addrs: Vec<Multiaddr>,
+
+    /// DHT peer to bootstrap from, as a multiaddr ending in `/p2p/<peer id>`;
+    /// can be given multiple times
+    #[arg(long = "bootstrap", global = true, value_name = "MULTIADDR")]
+    pub bootstrap_addrs: Vec<Multiaddr>,
+
+    /// Peer to stay connected to, as a multiaddr ending in `/p2p/<peer id>`,
+    /// redialed whenever the connection is lost; can be given multiple times
+    #[arg(long = "static-peer", global = true, value_name = "MULTIADDR")]
+    pub static_peers: Vec<Multiaddr>,
+
+    /// Relay to reserve a slot on, as a multiaddr ending in `/p2p/<peer id>`,
+    /// so peers can reach this node through it; can be given multiple times
+    #[arg(long = "relay", global = true, value_name = "MULTIADDR")]
+    pub relays: Vec<Multiaddr>,
+
+    /// Relay connections for peers that cannot be reached directly
+    #[arg(long, global = true)]
+    pub relay_server: bool,
+
+    /// Most reservations the relay server holds at once
+    #[arg(long, global = true, default_value_t = 128)]
+    pub relay_max_reservations: usize,
+
+    /// Most circuits the relay server carries at once
+    #[arg(long, global = true, default_value_t = 16)]
+    pub relay_max_circuits: usize,
+
+    /// Most circuits the relay server carries for a single peer
+    #[arg(long, global = true, default_value_t = 4)]
+    pub relay_max_circuits_per_peer: usize,
+
+    /// Longest a relayed circuit stays open, in seconds
+    #[arg(long, global = true, value_name = "SECS", default_value_t = 3600)]
+    pub relay_max_circuit_duration: u64,
+
+    /// Most bytes relayed over a circuit in total, 0 for no limit
+    #[arg(long, global = true, value_name = "BYTES", default_value_t = 0)]
+    pub relay_max_circuit_bytes: u64,
+
+    /// Swarm key file shared by a private network; only peers holding the same
+    /// key can connect, and QUIC is disabled
+    #[arg(long, global = true, value_name = "PATH")]
+    pub swarm_key: Option<PathBuf>,
+
+    /// Only connect to trusted peers, and ignore others discovered through mDNS
+    #[arg(long, global = true)]
+    pub private: bool,
+
+    /// Most connections accepted from other peers at once
+    #[arg(long, global = true, default_value_t = 128)]
+    pub max_inbound_connections: u32,
+
+    /// Most connections opened to other peers at once
+    #[arg(long, global = true, default_value_t = 128)]
+    pub max_outbound_connections: u32,
+
+    /// Most connections to or from a single peer at once
+    #[arg(long, global = true, default_value_t = 4)]
+    pub max_connections_per_peer: u32,
+
+    /// Share of the system memory above which new connections are refused
+    #[arg(
+        long,
+        global = true,
+        value_name = "PERCENT",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub max_memory_percent: u8,
+
+    /// Most file requests in flight on a single connection, in either direction
+    #[arg(long, global = true, default_value_t = 32)]
+    pub max_transfer_streams: usize,
+
+    /// Unix socket the node accepts JSON-RPC calls on (defaults to `<data-dir>/control.sock`)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Loopback address to serve the HTTP API and event stream on, e.g. `127.0.0.1:8080`
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub http: Option<SocketAddr>,
+
+    /// Local port to serve Prometheus metrics on, at `http://127.0.0.1:<PORT>/metrics`
+    #[arg(long, global = true, value_name = "PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Ask the router to forward the listening ports through UPnP
+    #[arg(long, global = true)]
+    pub upnp: bool,
+
+    /// Protocol version string advertised through identify
+    #[arg(long, global = true, default_value = DEFAULT_PROTOCOL_VERSION)]
+    pub protocol_version: String,
+
+    /// Minimum level of this application's log messages; other crates only log warnings and errors
+    #[arg(long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Log filter directives such as `rust_p2p_share=debug,libp2p_kad=trace`,
+    /// overriding `--log-level`
+    #[arg(long, global = true, env = "RUST_LOG", value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+
+    /// Format of log messages, which are written to stderr, or to a file in `tui` mode
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Subcommands of the application; `run` is used when none is given
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the node until interrupted
+    Run,
+    /// Run the node with a terminal interface showing peers, shares and
+    /// transfers; log messages go to `<data-dir>/tui.log`
+    Tui,
+    /// Add files or directories to the shared set
+    Share {
+        /// Paths to share
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// List shared files, either local ones or those of a remote peer
+    List {
+        /// Peer to ask for its shared files
+        #[arg(long)]
+        peer: Option<PeerId>,
+    },
+    /// Download a file from a peer
+    Get {
+        /// Peer holding the file
+        peer: PeerId,
+        /// Name or root hash of the file as reported by `list`
+        name: String,
+        /// Where to write the file (defaults to the file name in the current directory)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Replace the file at the output path if there is one
+        #[arg(long)]
+        force: bool,
+    },
+    /// Search the files shared by every peer on the network
+    Search {
+        /// Part of the file name, matched case-insensitively
+        name: Option<String>,
+        /// File extension, with or without the leading dot
+        #[arg(long = "ext", value_name = "EXT")]
+        extension: Option<String>,
+        /// Smallest file size in bytes
+        #[arg(long, value_name = "BYTES")]
+        min_size: Option<u64>,
+        /// Largest file size in bytes
+        #[arg(long, value_name = "BYTES")]
+        max_size: Option<u64>,
+        /// Root hash of the file
+        #[arg(long, value_name = "ROOT")]
+        hash: Option<ContentHash>,
+        /// How long to collect answers, in seconds
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Discover peers on the local network and print them
+    Peers {
+        /// How long to wait for discoveries, in seconds
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+        /// Print the peers recorded in the store instead of discovering
+        #[arg(long)]
+        known: bool,
+    },
+    /// Show the download history
+    Downloads,
+    /// Trust peers, which in private mode are the only ones allowed to connect;
+    /// prints the trusted peers when none are given
+    Trust {
+        /// Peers to trust
+        peers: Vec<PeerId>,
+        /// Stop trusting the peers instead
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Add peers to a named group that access rules can refer to; prints the
+    /// groups, or the members of the group, when none are given
+    Group {
+        /// Name of the group
+        name: Option<String>,
+        /// Peers to add
+        peers: Vec<PeerId>,
+        /// Remove the peers from the group instead
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Restrict who may see and download a shared file or folder; without a
+    /// path prints every rule, and with a path alone the rule that applies to it
+    Acl {
+        /// Shared file or folder, or a folder inside a shared one
+        path: Option<PathBuf>,
+        /// Let every peer access the path
+        #[arg(long, conflicts_with_all = ["peers", "groups"])]
+        public: bool,
+        /// Peer allowed to access the path; can be given multiple times
+        #[arg(long = "peer", value_name = "PEER_ID")]
+        peers: Vec<PeerId>,
+        /// Group whose members may access the path; can be given multiple times
+        #[arg(long = "group", value_name = "NAME")]
+        groups: Vec<String>,
+    },
+    /// Block peers from connecting; prints the blocked peers when none are given
+    Block {
+        /// Peers to block
+        peers: Vec<PeerId>,
+        /// Unblock the peers instead
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Call a method of the running node's control API and print the result
+    Rpc {
+        /// Method name, e.g. `files_list` or `download_status`
+        method: String,
+        /// Parameters as a JSON object
+        params: Option<String>,
+    },
+    /// Print this node's PeerId, optionally importing an existing key first
+    Id {
+        /// Import a protobuf-encoded libp2p key as this node's identity
+        #[arg(long, value_name = "PATH")]
+        import: Option<PathBuf>,
+    },
+}
+
+/// Verbosity of log messages, from least to most verbose
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// How log messages are written
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human-readable line per message
+    Text,
+    /// One JSON object per message, for log shipping
+    Json,
+}
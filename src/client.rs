@@ -0,0 +1,272 @@
+//! Short-lived client used by the `list` and `get` subcommands to talk to a single peer.
+
+use crate::access::PeerAccess;
+use crate::cli::Cli;
+use crate::download::Download;
+use crate::downloader::{DownloadEvent, Downloader};
+use crate::network::{self, MyBehaviour, MyBehaviourEvent};
+use crate::protocol::{FileEntry, FileRequest, FileResponse};
+use crate::store::Store;
+use futures::StreamExt;
+use libp2p::{
+    identity::Keypair,
+    kad,
+    mdns,
+    ping,
+    request_response,
+    swarm::{DialError, Swarm, SwarmEvent},
+    PeerId,
+};
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// How long to wait for the peer to be discovered and connected
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A node connected to one remote peer
+pub struct Client {
+    swarm: Swarm<MyBehaviour>,
+    peer: PeerId,
+    /// Every allowed peer reported by mDNS, used as extra download sources
+    discovered: HashSet<PeerId>,
+    access: PeerAccess,
+}
+
+impl Client {
+    /// Starts a node and connects to `peer` once it is discovered through mDNS
+    /// or found in the DHT reached through the bootstrap peers.
+    pub async fn connect(
+        cli: &Cli,
+        local_key: &Keypair,
+        access: PeerAccess,
+        peer: PeerId,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut swarm = network::build_swarm(local_key, cli, &access)?;
+        network::listen(&mut swarm, cli)?;
+        // Looking up the peer in the DHT connects to it if anyone knows its addresses
+        if !cli.bootstrap_addrs.is_empty() {
+            network::bootstrap(&mut swarm, &cli.bootstrap_addrs)?;
+            swarm.behaviour_mut().kad.get_closest_peers(peer);
+        }
+
+        let mut discovered = HashSet::new();
+        let deadline = tokio::time::sleep(CONNECT_TIMEOUT);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => {
+                    return Err(format!("timed out connecting to {}", peer).into());
+                }
+                event = swarm.select_next_some() => match event {
+                    // Dial the peer as soon as mDNS reports it
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                        discovered.extend(
+                            found.into_iter().map(|(peer_id, _)| peer_id).filter(|peer_id| access.allows(peer_id)),
+                        );
+                        if discovered.contains(&peer) && !swarm.is_connected(&peer) {
+                            match swarm.dial(peer) {
+                                // Already dialing it after an earlier discovery of another address
+                                Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+                                Err(e) => return Err(e.into()),
+                            }
+                        }
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == peer => {
+                        return Ok(Client { swarm, peer, discovered, access });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Sends `request` to the peer and waits for the response.
+    pub async fn request(&mut self, request: FileRequest) -> Result<FileResponse, Box<dyn Error>> {
+        let request_id = self.swarm.behaviour_mut().file_share.send_request(&self.peer, request);
+        loop {
+            match self.swarm.select_next_some().await {
+                SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::Message {
+                    message: request_response::Message::Response { request_id: id, response },
+                    ..
+                })) if id == request_id => return Ok(response),
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                    let access = &self.access;
+                    self.discovered.extend(
+                        found.into_iter().map(|(peer_id, _)| peer_id).filter(|peer_id| access.allows(peer_id)),
+                    );
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(
+                    request_response::Event::OutboundFailure { request_id: id, error, .. },
+                )) if id == request_id => {
+                    return Err(format!("request to {} failed: {}", self.peer, error).into());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Fetches the peer's shared file list, page by page.
+    pub async fn list(&mut self) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+        let mut files = Vec::new();
+        loop {
+            let offset = u32::try_from(files.len())?;
+            match self.request(FileRequest::List { offset }).await? {
+                // An empty page ends the list even if the peer claims there is more
+                FileResponse::List { entries, more } if more && !entries.is_empty() => files.extend(entries),
+                FileResponse::List { entries, .. } => {
+                    files.extend(entries);
+                    return Ok(files);
+                }
+                FileResponse::Denied(file) => return Err(format!("access to {} denied", file).into()),
+                FileResponse::Error(message) => return Err(message.into()),
+                other => return Err(format!("unexpected response: {:?}", other).into()),
+            }
+        }
+    }
+
+    /// Downloads `file` (a shared name or root hash) into `output`, verifying every chunk.
+    /// An existing file at the output path is only replaced if `force` is set.
+    ///
+    /// Other peers discovered through mDNS that hold the same content are used
+    /// as additional sources. The download is recorded in the store in
+    /// `data_dir`, so if it is interrupted it is resumed by running `get` again
+    /// or by a node started with `run`.
+    pub async fn download(
+        &mut self,
+        data_dir: &Path,
+        file: &str,
+        output: Option<&Path>,
+        force: bool,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let manifest = match self.request(FileRequest::Manifest { file: file.to_string() }).await? {
+            FileResponse::Manifest(manifest) => manifest,
+            FileResponse::Denied(file) => return Err(format!("access to {} denied", file).into()),
+            FileResponse::Error(message) => return Err(message.into()),
+            other => return Err(format!("unexpected response: {:?}", other).into()),
+        };
+        let output = match output {
+            Some(output) => output.to_path_buf(),
+            None => default_output_path(&manifest.name)?,
+        };
+
+        let output = std::path::absolute(output)?;
+        // A partial download only ever exists next to the output, as `<output>.part`
+        if output.exists() && !force {
+            return Err(format!(
+                "{} already exists; pass --force to replace it or -o to save the file elsewhere",
+                output.display()
+            )
+            .into());
+        }
+
+        let download = Download::open(manifest, &output)?;
+        let total = download.manifest().chunk_count();
+        if download.completed_chunks() > 0 {
+            eprintln!("Resuming with {}/{} chunks already verified", download.completed_chunks(), total);
+        }
+        let mut downloader = Downloader::new(Rc::new(Store::open(data_dir)?));
+        let root = downloader.start(download)?;
+        downloader.add_provider(root, self.peer);
+        for peer in &self.discovered {
+            downloader.probe_peer(*peer, &mut self.swarm.behaviour_mut().file_share);
+        }
+        let local_peer_id = *self.swarm.local_peer_id();
+        let mut provider_lookup = Some(
+            self.swarm
+                .behaviour_mut()
+                .kad
+                .get_providers(network::provider_key(&root)),
+        );
+        // Reported if every provider ends up rejected
+        let mut rejected = None;
+
+        loop {
+            while let Some(event) = downloader.next_event() {
+                match event {
+                    DownloadEvent::Progress { completed, total, .. } => {
+                        eprint!("\rDownloaded {}/{} chunks", completed, total);
+                    }
+                    DownloadEvent::Completed { path, .. } => {
+                        eprintln!();
+                        return Ok(path);
+                    }
+                    // The chunk is requested again
+                    DownloadEvent::ChunkFailed { .. } => {}
+                    // The remaining providers take over its chunks
+                    DownloadEvent::PeerRejected { peer, error, .. } => {
+                        eprintln!();
+                        eprintln!("No longer downloading from {}: {}", peer, error);
+                        rejected = Some(format!("rejected data from {}: {}", peer, error));
+                    }
+                    DownloadEvent::Failed { error, .. } => {
+                        eprintln!();
+                        return Err(error.into());
+                    }
+                }
+            }
+            if downloader.provider_count(&root) == 0 && !downloader.is_probing(&root) && provider_lookup.is_none() {
+                return Err(rejected.unwrap_or_else(|| "no connected peer can provide the file".to_string()).into());
+            }
+            downloader.poll(&mut self.swarm.behaviour_mut().file_share);
+
+            match self.swarm.select_next_some().await {
+                // Other peers holding the same content become additional sources
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                    for (peer_id, _) in found.into_iter().filter(|(peer_id, _)| self.access.allows(peer_id)) {
+                        self.discovered.insert(peer_id);
+                        downloader.probe_peer(peer_id, &mut self.swarm.behaviour_mut().file_share);
+                    }
+                }
+                // So do peers announcing the content in the DHT
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::GetProviders(result),
+                    step,
+                    ..
+                })) if Some(id) == provider_lookup => {
+                    if let Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) = result {
+                        for provider in providers.into_iter().filter(|provider| *provider != local_peer_id) {
+                            downloader.probe_peer(provider, &mut self.swarm.behaviour_mut().file_share);
+                        }
+                    }
+                    if step.last {
+                        provider_lookup = None;
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Ping(ping::Event {
+                    peer,
+                    result: Ok(rtt),
+                    ..
+                })) => {
+                    downloader.record_rtt(peer, rtt);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::Message {
+                    message: request_response::Message::Response { request_id, response },
+                    ..
+                })) => {
+                    downloader.on_response(request_id, response);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(
+                    request_response::Event::OutboundFailure { request_id, .. },
+                )) => {
+                    downloader.on_failure(request_id);
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    downloader.peer_disconnected(&peer_id);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the final component of a shared file name, used as the default download path.
+fn default_output_path(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    Path::new(name)
+        .file_name()
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("cannot derive a file name from {}", name).into())
+}
@@ -0,0 +1,342 @@
+//! Chunk-by-chunk downloads that survive restarts.
+//!
+//! Every chunk is checked against the manifest before it is written, so
+//! corrupted or malicious data never reaches the output file. Data is written
+//! to `<output>.part`, and `<output>.part.state` records the manifest and which
+//! chunks have been verified. When a download is reopened, the chunks recorded
+//! as verified are hashed again from disk and only the rest is fetched.
+
+use crate::manifest::Manifest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two writes of the state file while chunks keep arriving
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Why a received chunk could not be stored
+#[derive(Debug)]
+pub enum ChunkError {
+    /// The data does not match the manifest; the sender is at fault
+    Invalid(Box<dyn Error>),
+    /// Writing the data locally failed
+    Io(io::Error),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::Invalid(e) => write!(f, "invalid chunk: {}", e),
+            ChunkError::Io(e) => write!(f, "cannot write chunk: {}", e),
+        }
+    }
+}
+
+impl Error for ChunkError {}
+
+/// Contents of the `.part.state` file
+#[derive(Debug, Serialize, Deserialize)]
+struct DownloadState {
+    manifest: Manifest,
+    /// Verified chunks, as ranges of chunk indices
+    verified: Vec<Range<u32>>,
+}
+
+/// State of a single file download
+pub struct Download {
+    manifest: Manifest,
+    output: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    /// Chunks that still need to be requested
+    missing: BTreeSet<u32>,
+    /// Chunks that were requested but not received yet
+    in_flight: HashSet<u32>,
+    last_saved: Instant,
+}
+
+impl Download {
+    /// Starts or resumes downloading the file described by `manifest` into `output`.
+    ///
+    /// An existing partial download is resumed only if it is for the same root hash.
+    pub fn open(manifest: Manifest, output: &Path) -> Result<Self, Box<dyn Error>> {
+        manifest.verify()?;
+
+        let state = read_state(&state_path(output)).ok();
+        let verified = match state {
+            Some(state) if state.manifest.root == manifest.root => state.verified,
+            _ => Vec::new(),
+        };
+        Self::open_with(manifest, output, &verified)
+    }
+
+    /// Resumes the download into `output` from its state file.
+    pub fn resume(output: &Path) -> Result<Self, Box<dyn Error>> {
+        let state = read_state(&state_path(output))?;
+        state.manifest.verify()?;
+        Self::open_with(state.manifest, output, &state.verified)
+    }
+
+    /// Opens the partial file, re-verifying the chunks listed in `verified`.
+    fn open_with(manifest: Manifest, output: &Path, verified: &[Range<u32>]) -> Result<Self, Box<dyn Error>> {
+        let part_path = part_path(output);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part_path)?;
+        file.set_len(manifest.size)?;
+
+        let mut missing: BTreeSet<u32> = (0..manifest.chunk_count()).collect();
+        let mut buffer = Vec::new();
+        for index in verified.iter().flat_map(|range| range.clone()) {
+            if index >= manifest.chunk_count() {
+                continue;
+            }
+            buffer.resize(manifest.chunk_len(index) as usize, 0);
+            file.seek(SeekFrom::Start(manifest.chunk_offset(index)))?;
+            file.read_exact(&mut buffer)?;
+            if manifest.verify_chunk(index, &buffer).is_ok() {
+                missing.remove(&index);
+            }
+        }
+
+        let mut download = Download {
+            manifest,
+            output: output.to_path_buf(),
+            part_path,
+            file,
+            missing,
+            in_flight: HashSet::new(),
+            last_saved: Instant::now(),
+        };
+        download.save_state()?;
+        Ok(download)
+    }
+
+    /// The manifest being downloaded.
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Where the finished file will be written.
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// Picks the next chunk to request and marks it as in flight.
+    pub fn next_chunk(&mut self) -> Option<u32> {
+        let index = self.missing.pop_first()?;
+        self.in_flight.insert(index);
+        Some(index)
+    }
+
+    /// Number of chunks currently requested and not yet received.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Verifies a received chunk and writes it to the partial file.
+    ///
+    /// A chunk that cannot be stored is put back in the missing set and the
+    /// error is returned, so the caller can decide whether to retry elsewhere.
+    pub fn complete_chunk(&mut self, index: u32, data: &[u8]) -> Result<(), ChunkError> {
+        if !self.in_flight.remove(&index) {
+            return Err(ChunkError::Invalid(format!("chunk {} was not requested", index).into()));
+        }
+        if let Err(e) = self.manifest.verify_chunk(index, data) {
+            self.missing.insert(index);
+            return Err(ChunkError::Invalid(e));
+        }
+        if let Err(e) = self.write_chunk(index, data) {
+            self.missing.insert(index);
+            return Err(ChunkError::Io(e));
+        }
+
+        if self.last_saved.elapsed() >= STATE_SAVE_INTERVAL {
+            self.save_state().map_err(ChunkError::Io)?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, index: u32, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.manifest.chunk_offset(index)))?;
+        self.file.write_all(data)
+    }
+
+    /// Returns an in-flight chunk to the missing set, e.g. after a failed request.
+    pub fn fail_chunk(&mut self, index: u32) {
+        if self.in_flight.remove(&index) {
+            self.missing.insert(index);
+        }
+    }
+
+    /// Number of chunks verified and written so far.
+    pub fn completed_chunks(&self) -> u32 {
+        self.manifest.chunk_count() - self.missing.len() as u32 - self.in_flight.len() as u32
+    }
+
+    /// Whether every chunk has been verified and written.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Records the verified chunks in the state file.
+    ///
+    /// The data is synced first so the state never claims chunks that are not on disk.
+    pub fn save_state(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        let state = DownloadState {
+            manifest: self.manifest.clone(),
+            verified: self.verified_ranges(),
+        };
+        let path = state_path(&self.output);
+        let tmp = path.with_extension("state.tmp");
+        fs::write(&tmp, serde_json::to_vec(&state)?)?;
+        fs::rename(&tmp, &path)?;
+        self.last_saved = Instant::now();
+        Ok(())
+    }
+
+    /// Flushes the partial file, moves it to the output path and removes the state file.
+    pub fn finish(self) -> Result<PathBuf, Box<dyn Error>> {
+        if !self.is_complete() {
+            return Err("download is not complete".into());
+        }
+        self.file.sync_all()?;
+        fs::rename(&self.part_path, &self.output)?;
+        let _ = fs::remove_file(state_path(&self.output));
+        Ok(self.output)
+    }
+
+    /// Chunks neither missing nor in flight, collapsed into ranges.
+    fn verified_ranges(&self) -> Vec<Range<u32>> {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        for index in 0..self.manifest.chunk_count() {
+            if self.missing.contains(&index) || self.in_flight.contains(&index) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == index => range.end = index + 1,
+                _ => ranges.push(index..index + 1),
+            }
+        }
+        ranges
+    }
+}
+
+fn read_state(path: &Path) -> Result<DownloadState, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Path of the partial file for `output`, e.g. `cat.jpg.part`.
+pub fn part_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_os_string();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Path of the state file for `output`, e.g. `cat.jpg.part.state`.
+fn state_path(output: &Path) -> PathBuf {
+    let mut name = part_path(output).into_os_string();
+    name.push(".state");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::CHUNK_SIZE;
+
+    /// Writes a file spanning three chunks and returns its contents and manifest.
+    fn source(dir: &Path) -> (Vec<u8>, Manifest) {
+        let data: Vec<u8> = (0..CHUNK_SIZE as usize * 2 + 1000).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("source.bin");
+        fs::write(&path, &data).unwrap();
+        let manifest = Manifest::from_file(&path, "source.bin".to_string()).unwrap();
+        (data, manifest)
+    }
+
+    fn chunk<'a>(data: &'a [u8], manifest: &Manifest, index: u32) -> &'a [u8] {
+        let offset = manifest.chunk_offset(index) as usize;
+        &data[offset..offset + manifest.chunk_len(index) as usize]
+    }
+
+    /// Downloads chunks 0 and 2 into `output`, leaving chunk 1 missing.
+    fn interrupted(data: &[u8], manifest: &Manifest, output: &Path) {
+        let mut download = Download::open(manifest.clone(), output).unwrap();
+        for _ in 0..3 {
+            download.next_chunk().unwrap();
+        }
+        download.fail_chunk(1);
+        download.complete_chunk(0, chunk(data, manifest, 0)).unwrap();
+        download.complete_chunk(2, chunk(data, manifest, 2)).unwrap();
+        download.save_state().unwrap();
+    }
+
+    #[test]
+    fn resume_fetches_only_missing_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, manifest) = source(dir.path());
+        let output = dir.path().join("output.bin");
+        interrupted(&data, &manifest, &output);
+
+        let mut download = Download::resume(&output).unwrap();
+        assert_eq!(download.manifest().root, manifest.root);
+        assert_eq!(download.completed_chunks(), 2);
+        assert_eq!(download.next_chunk(), Some(1));
+        assert_eq!(download.next_chunk(), None);
+        download.complete_chunk(1, chunk(&data, &manifest, 1)).unwrap();
+
+        assert_eq!(download.finish().unwrap(), output);
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert!(!part_path(&output).exists());
+        assert!(!state_path(&output).exists());
+    }
+
+    #[test]
+    fn resume_refetches_chunks_corrupted_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, manifest) = source(dir.path());
+        let output = dir.path().join("output.bin");
+        interrupted(&data, &manifest, &output);
+
+        let mut part = fs::read(part_path(&output)).unwrap();
+        part[10] ^= 0xff;
+        fs::write(part_path(&output), part).unwrap();
+
+        let mut download = Download::resume(&output).unwrap();
+        assert_eq!(download.completed_chunks(), 1);
+        assert_eq!(download.next_chunk(), Some(0));
+        assert_eq!(download.next_chunk(), Some(1));
+        assert_eq!(download.next_chunk(), None);
+    }
+
+    #[test]
+    fn open_discards_state_for_other_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, manifest) = source(dir.path());
+        let output = dir.path().join("output.bin");
+        interrupted(&data, &manifest, &output);
+
+        let other_path = dir.path().join("other.bin");
+        fs::write(&other_path, b"other contents").unwrap();
+        let other = Manifest::from_file(&other_path, "other.bin".to_string()).unwrap();
+        let download = Download::open(other, &output).unwrap();
+        assert_eq!(download.completed_chunks(), 0);
+        assert_eq!(read_state(&state_path(&output)).unwrap().verified, Vec::<Range<u32>>::new());
+    }
+
+    #[test]
+    fn resume_without_state_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Download::resume(&dir.path().join("missing.bin")).is_err());
+    }
+}
@@ -0,0 +1,668 @@
+//! Drives downloads over the file-sharing protocol.
+//!
+//! The downloader finds peers holding a file by asking them for its manifest
+//! and then requests the missing chunks from them. When several peers hold the
+//! same content, different chunks are fetched from different peers in
+//! parallel, with fewer requests sent to peers whose ping RTT marks them as
+//! slow; chunks in flight to a peer that disconnects are handed to the others.
+//! Downloads are recorded in the [`Store`], so a restarted node resumes the
+//! unfinished ones as soon as a peer holding the content shows up.
+
+use crate::download::{ChunkError, Download};
+use crate::manifest::ContentHash;
+use crate::network::FileShareBehaviour;
+use crate::protocol::{FileRequest, FileResponse};
+use crate::store::{DownloadStatus, Store};
+use libp2p::{request_response::OutboundRequestId, PeerId};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Number of chunk requests kept in flight per download, across all its providers
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Number of chunk requests kept in flight per peer, across all downloads
+const MAX_IN_FLIGHT_PER_PEER: usize = 4;
+
+/// Number of chunk requests kept in flight to a peer considered slow
+const MAX_IN_FLIGHT_SLOW_PEER: usize = 1;
+
+/// A peer whose RTT exceeds the fastest provider's by this factor is considered slow
+const SLOW_PEER_FACTOR: u32 = 4;
+
+/// RTTs below this are never considered slow, so LAN jitter does not penalise anyone
+const SLOW_PEER_MIN_RTT: Duration = Duration::from_millis(50);
+
+/// Number of consecutive failed chunk requests tolerated from a peer before it is dropped as a source
+const MAX_FAILED_REQUESTS: u32 = 8;
+
+/// Progress reported by the [`Downloader`]
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+    /// A chunk was verified and written
+    Progress {
+        root: ContentHash,
+        completed: u32,
+        total: u32,
+        /// Size of the chunk
+        bytes: u64,
+    },
+    /// A chunk request failed or returned bad data; the chunk is requested again
+    ChunkFailed {
+        root: ContentHash,
+        peer: PeerId,
+        error: String,
+    },
+    /// All chunks were verified and the file moved into place
+    Completed { root: ContentHash, path: PathBuf },
+    /// A peer sent bad data or kept failing and is no longer used for this download
+    PeerRejected {
+        root: ContentHash,
+        peer: PeerId,
+        error: String,
+    },
+    /// The download cannot continue, e.g. because the output file is not writable
+    Failed { root: ContentHash, error: String },
+}
+
+/// A download together with the peers known to hold its content
+struct ActiveDownload {
+    download: Download,
+    /// Peers that returned the manifest for this root
+    providers: HashSet<PeerId>,
+    /// Peers already asked whether they hold the content
+    probed: HashSet<PeerId>,
+    /// Failed requests per provider since its last verified chunk
+    failures: HashMap<PeerId, u32>,
+}
+
+/// An outstanding request sent by the downloader
+enum PendingRequest {
+    /// Asking a peer for the manifest to learn whether it holds the content
+    Probe { root: ContentHash, peer: PeerId },
+    /// Fetching one chunk
+    Chunk {
+        root: ContentHash,
+        index: u32,
+        peer: PeerId,
+    },
+}
+
+impl PendingRequest {
+    fn peer(&self) -> &PeerId {
+        match self {
+            PendingRequest::Probe { peer, .. } | PendingRequest::Chunk { peer, .. } => peer,
+        }
+    }
+}
+
+/// Schedules chunk requests for all active downloads
+pub struct Downloader {
+    store: Rc<Store>,
+    downloads: HashMap<ContentHash, ActiveDownload>,
+    requests: HashMap<OutboundRequestId, PendingRequest>,
+    /// Chunk requests in flight per peer
+    peer_load: HashMap<PeerId, usize>,
+    /// Smoothed ping round-trip time per peer
+    rtts: HashMap<PeerId, Duration>,
+    events: VecDeque<DownloadEvent>,
+}
+
+impl Downloader {
+    /// Creates a downloader with no active downloads, recording new ones in `store`.
+    pub fn new(store: Rc<Store>) -> Self {
+        Downloader {
+            store,
+            downloads: HashMap::new(),
+            requests: HashMap::new(),
+            peer_load: HashMap::new(),
+            rtts: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Creates a downloader and resumes every unfinished download recorded in `store`.
+    pub fn load(store: Rc<Store>) -> Result<Self, Box<dyn Error>> {
+        let mut downloader = Self::new(store);
+        for output in downloader.store.active_downloads()? {
+            match Download::resume(&output) {
+                Ok(download) => {
+                    info!(
+                        output = %output.display(),
+                        completed = download.completed_chunks(),
+                        total = download.manifest().chunk_count(),
+                        "Resuming download"
+                    );
+                    downloader.start(download)?;
+                }
+                Err(e) => {
+                    warn!(output = %output.display(), error = %e, "Dropping unfinished download");
+                    let error = e.to_string();
+                    downloader
+                        .store
+                        .download_finished(&output, DownloadStatus::Abandoned, Some(&error))?;
+                }
+            }
+        }
+        Ok(downloader)
+    }
+
+    /// Adds a download and records it in the store so it survives restarts.
+    pub fn start(&mut self, download: Download) -> Result<ContentHash, Box<dyn Error>> {
+        self.store.download_started(download.output(), download.manifest())?;
+        Ok(self.insert(download))
+    }
+
+    fn insert(&mut self, download: Download) -> ContentHash {
+        let root = download.manifest().root;
+        self.downloads.insert(
+            root,
+            ActiveDownload {
+                download,
+                providers: HashSet::new(),
+                probed: HashSet::new(),
+                failures: HashMap::new(),
+            },
+        );
+        // Empty files and fully verified resumes need no requests at all
+        self.finish_if_complete(root);
+        root
+    }
+
+    /// Records that `peer` holds the content identified by `root`.
+    pub fn add_provider(&mut self, root: ContentHash, peer: PeerId) {
+        if let Some(active) = self.downloads.get_mut(&root) {
+            active.probed.insert(peer);
+            active.providers.insert(peer);
+        }
+    }
+
+    /// Asks `peer` for the manifest of every active download it was not asked about yet.
+    pub fn probe_peer(&mut self, peer: PeerId, behaviour: &mut FileShareBehaviour) {
+        for (root, active) in &mut self.downloads {
+            if active.probed.insert(peer) {
+                let request = FileRequest::Manifest {
+                    file: root.to_string(),
+                };
+                let request_id = behaviour.send_request(&peer, request);
+                self.requests
+                    .insert(request_id, PendingRequest::Probe { root: *root, peer });
+            }
+        }
+    }
+
+    /// Sends chunk requests until every download with a provider has enough in
+    /// flight, spreading them over providers within their concurrency limits.
+    pub fn poll(&mut self, behaviour: &mut FileShareBehaviour) {
+        for (root, active) in &mut self.downloads {
+            while active.download.in_flight() < MAX_IN_FLIGHT {
+                let Some(peer) = pick_provider(&active.providers, &self.peer_load, &self.rtts) else {
+                    break;
+                };
+                let Some(index) = active.download.next_chunk() else {
+                    break;
+                };
+                let request_id = behaviour.send_request(&peer, FileRequest::Chunk { root: *root, index });
+                self.requests.insert(
+                    request_id,
+                    PendingRequest::Chunk {
+                        root: *root,
+                        index,
+                        peer,
+                    },
+                );
+                *self.peer_load.entry(peer).or_default() += 1;
+            }
+        }
+    }
+
+    /// Records a ping round-trip time, smoothed over recent measurements.
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        self.rtts
+            .entry(peer)
+            .and_modify(|smoothed| *smoothed = (*smoothed * 3 + rtt) / 4)
+            .or_insert(rtt);
+    }
+
+    /// Handles a response. Returns `false` if the request was not sent by the downloader.
+    pub fn on_response(&mut self, request_id: OutboundRequestId, response: FileResponse) -> bool {
+        let Some(pending) = self.take_request(&request_id) else {
+            return false;
+        };
+        match pending {
+            PendingRequest::Probe { root, peer } => {
+                if let Some(active) = self.downloads.get_mut(&root) {
+                    if matches!(response, FileResponse::Manifest(ref manifest) if manifest.root == root) {
+                        active.providers.insert(peer);
+                    }
+                }
+            }
+            PendingRequest::Chunk { root, index, peer } => {
+                let Some(active) = self.downloads.get_mut(&root) else {
+                    return true;
+                };
+                let result = match response {
+                    FileResponse::Chunk { data, .. } => active.download.complete_chunk(index, &data),
+                    FileResponse::Denied(_) => {
+                        active.download.fail_chunk(index);
+                        Err(ChunkError::Invalid("access denied".into()))
+                    }
+                    FileResponse::Error(message) => {
+                        active.download.fail_chunk(index);
+                        Err(ChunkError::Invalid(message.into()))
+                    }
+                    other => {
+                        active.download.fail_chunk(index);
+                        Err(ChunkError::Invalid(format!("unexpected response: {:?}", other).into()))
+                    }
+                };
+                match result {
+                    Ok(()) => {
+                        active.failures.remove(&peer);
+                        self.chunk_completed(root, index);
+                    }
+                    // A peer that serves data not matching the manifest is not asked again
+                    Err(ChunkError::Invalid(e)) => {
+                        self.events.push_back(DownloadEvent::ChunkFailed {
+                            root,
+                            peer,
+                            error: e.to_string(),
+                        });
+                        self.reject_provider(root, peer, e.to_string());
+                    }
+                    // Local failures stop the download; it stays recorded as active and is resumed later
+                    Err(ChunkError::Io(e)) => {
+                        self.downloads.remove(&root);
+                        self.events.push_back(DownloadEvent::Failed {
+                            root,
+                            error: e.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Handles a failed request. Returns `false` if the request was not sent by the downloader.
+    pub fn on_failure(&mut self, request_id: OutboundRequestId) -> bool {
+        let Some(pending) = self.take_request(&request_id) else {
+            return false;
+        };
+        match pending {
+            // The peer may not have been reachable yet; ask again once it connects
+            PendingRequest::Probe { root, peer } => {
+                if let Some(active) = self.downloads.get_mut(&root) {
+                    active.probed.remove(&peer);
+                }
+            }
+            PendingRequest::Chunk { root, index, peer } => {
+                if let Some(active) = self.downloads.get_mut(&root) {
+                    active.download.fail_chunk(index);
+                    self.events.push_back(DownloadEvent::ChunkFailed {
+                        root,
+                        peer,
+                        error: "request failed".to_string(),
+                    });
+                    let failures = active.failures.entry(peer).or_default();
+                    *failures += 1;
+                    if *failures > MAX_FAILED_REQUESTS {
+                        self.reject_provider(root, peer, "too many failed requests".to_string());
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Forgets a disconnected peer so it is probed again when it comes back,
+    /// and hands its in-flight chunks back to be requested from other providers.
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        let orphaned: Vec<OutboundRequestId> = self
+            .requests
+            .iter()
+            .filter(|(_, pending)| pending.peer() == peer)
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        for request_id in orphaned {
+            if let Some(PendingRequest::Chunk { root, index, .. }) = self.take_request(&request_id) {
+                if let Some(active) = self.downloads.get_mut(&root) {
+                    active.download.fail_chunk(index);
+                }
+            }
+        }
+
+        for active in self.downloads.values_mut() {
+            active.providers.remove(peer);
+            active.probed.remove(peer);
+        }
+        self.rtts.remove(peer);
+    }
+
+    /// Root hashes of the downloads in progress.
+    pub fn roots(&self) -> impl Iterator<Item = &ContentHash> {
+        self.downloads.keys()
+    }
+
+    /// Output paths of the downloads in progress.
+    pub fn outputs(&self) -> impl Iterator<Item = &Path> {
+        self.downloads.values().map(|active| active.download.output())
+    }
+
+    /// Number of peers currently used as sources for `root`.
+    pub fn provider_count(&self, root: &ContentHash) -> usize {
+        self.downloads
+            .get(root)
+            .map_or(0, |active| active.providers.len())
+    }
+
+    /// Verified and total chunk counts of the download of `root`, if it is in progress.
+    pub fn progress(&self, root: &ContentHash) -> Option<(u32, u32)> {
+        let download = &self.downloads.get(root)?.download;
+        Some((download.completed_chunks(), download.manifest().chunk_count()))
+    }
+
+    /// Chunk size of the download of `root`, if it is in progress.
+    pub fn chunk_size(&self, root: &ContentHash) -> Option<u32> {
+        Some(self.downloads.get(root)?.download.manifest().chunk_size)
+    }
+
+    /// Whether peers are still being asked if they hold `root`.
+    pub fn is_probing(&self, root: &ContentHash) -> bool {
+        self.requests
+            .values()
+            .any(|pending| matches!(pending, PendingRequest::Probe { root: probed, .. } if probed == root))
+    }
+
+    /// Removes an outstanding request, updating the per-peer load.
+    fn take_request(&mut self, request_id: &OutboundRequestId) -> Option<PendingRequest> {
+        let pending = self.requests.remove(request_id)?;
+        if let PendingRequest::Chunk { peer, .. } = &pending {
+            if let Some(load) = self.peer_load.get_mut(peer) {
+                *load = load.saturating_sub(1);
+                if *load == 0 {
+                    self.peer_load.remove(peer);
+                }
+            }
+        }
+        Some(pending)
+    }
+
+    /// Takes the next pending event.
+    pub fn next_event(&mut self) -> Option<DownloadEvent> {
+        self.events.pop_front()
+    }
+
+    fn chunk_completed(&mut self, root: ContentHash, index: u32) {
+        let Some(active) = self.downloads.get(&root) else {
+            return;
+        };
+        self.events.push_back(DownloadEvent::Progress {
+            root,
+            completed: active.download.completed_chunks(),
+            total: active.download.manifest().chunk_count(),
+            bytes: active.download.manifest().chunk_len(index),
+        });
+        self.finish_if_complete(root);
+    }
+
+    /// Moves a fully verified download into place and reports the outcome.
+    fn finish_if_complete(&mut self, root: ContentHash) {
+        if !self
+            .downloads
+            .get(&root)
+            .is_some_and(|active| active.download.is_complete())
+        {
+            return;
+        }
+
+        let active = self.downloads.remove(&root).expect("download is active");
+        let output = active.download.output().to_path_buf();
+        let event = match active.download.finish() {
+            Ok(path) => DownloadEvent::Completed { root, path },
+            Err(e) => DownloadEvent::Failed {
+                root,
+                error: e.to_string(),
+            },
+        };
+        // A failed rename leaves the download active so it is retried on the next start
+        if matches!(event, DownloadEvent::Completed { .. }) {
+            if let Err(e) = self.store.download_finished(&output, DownloadStatus::Completed, None) {
+                warn!(output = %output.display(), error = %e, "Cannot record completed download");
+            }
+        }
+        self.events.push_back(event);
+    }
+
+    fn reject_provider(&mut self, root: ContentHash, peer: PeerId, error: String) {
+        if let Some(active) = self.downloads.get_mut(&root) {
+            if active.providers.remove(&peer) {
+                self.events
+                    .push_back(DownloadEvent::PeerRejected { root, peer, error });
+            }
+        }
+    }
+}
+
+/// Picks the provider with spare capacity that has the fewest requests in
+/// flight, preferring lower round-trip times on ties.
+fn pick_provider(
+    providers: &HashSet<PeerId>,
+    peer_load: &HashMap<PeerId, usize>,
+    rtts: &HashMap<PeerId, Duration>,
+) -> Option<PeerId> {
+    let fastest = providers.iter().filter_map(|peer| rtts.get(peer)).min().copied();
+    providers
+        .iter()
+        .copied()
+        .filter(|peer| {
+            let load = peer_load.get(peer).copied().unwrap_or(0);
+            load < max_in_flight(rtts.get(peer).copied(), fastest)
+        })
+        .min_by_key(|peer| {
+            let load = peer_load.get(peer).copied().unwrap_or(0);
+            (load, rtts.get(peer).copied().unwrap_or(Duration::MAX))
+        })
+}
+
+/// Concurrency limit for a peer with the given RTT, compared to the fastest provider.
+fn max_in_flight(rtt: Option<Duration>, fastest: Option<Duration>) -> usize {
+    match (rtt, fastest) {
+        (Some(rtt), Some(fastest)) if rtt > SLOW_PEER_MIN_RTT && rtt > fastest * SLOW_PEER_FACTOR => {
+            MAX_IN_FLIGHT_SLOW_PEER
+        }
+        _ => MAX_IN_FLIGHT_PER_PEER,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{Manifest, CHUNK_SIZE};
+    use crate::protocol::FILE_PROTOCOL;
+    use libp2p::request_response::{self, ProtocolSupport};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// A download of a file of `CHUNKS` chunks, with the requests it sends
+    /// captured by a behaviour that is never connected.
+    struct Fixture {
+        _dir: TempDir,
+        data: Vec<u8>,
+        root: ContentHash,
+        downloader: Downloader,
+        behaviour: FileShareBehaviour,
+    }
+
+    const CHUNKS: u32 = 20;
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let data: Vec<u8> = (0..CHUNKS as usize * CHUNK_SIZE as usize).map(|i| (i % 251) as u8).collect();
+            let source = dir.path().join("source");
+            fs::write(&source, &data).unwrap();
+            let manifest = Manifest::from_file(&source, "file".to_string()).unwrap();
+
+            let mut downloader = Downloader::new(Rc::new(Store::open(dir.path()).unwrap()));
+            let download = Download::open(manifest, &dir.path().join("output")).unwrap();
+            let root = downloader.start(download).unwrap();
+            let behaviour = FileShareBehaviour::new(
+                [(FILE_PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            );
+            Fixture {
+                _dir: dir,
+                data,
+                root,
+                downloader,
+                behaviour,
+            }
+        }
+
+        fn poll(&mut self) {
+            self.downloader.poll(&mut self.behaviour);
+        }
+
+        /// Chunk requests in flight to `peer`, by request id.
+        fn requests_to(&self, peer: &PeerId) -> Vec<(OutboundRequestId, u32)> {
+            let mut requests: Vec<_> = self
+                .downloader
+                .requests
+                .iter()
+                .filter_map(|(request_id, pending)| match pending {
+                    PendingRequest::Chunk { index, peer: to, .. } if to == peer => Some((*request_id, *index)),
+                    _ => None,
+                })
+                .collect();
+            requests.sort_by_key(|(_, index)| *index);
+            requests
+        }
+
+        /// Answers a chunk request with the right data.
+        fn answer(&mut self, request_id: OutboundRequestId, index: u32) {
+            let start = index as usize * CHUNK_SIZE as usize;
+            let response = FileResponse::Chunk {
+                root: self.root,
+                index,
+                data: self.data[start..start + CHUNK_SIZE as usize].to_vec(),
+            };
+            assert!(self.downloader.on_response(request_id, response));
+        }
+
+        /// Fails every request in flight to `peer`.
+        fn fail_all(&mut self, peer: &PeerId) {
+            for (request_id, _) in self.requests_to(peer) {
+                assert!(self.downloader.on_failure(request_id));
+            }
+        }
+
+        fn rejected(&mut self) -> Vec<PeerId> {
+            let mut rejected = Vec::new();
+            while let Some(event) = self.downloader.next_event() {
+                if let DownloadEvent::PeerRejected { peer, .. } = event {
+                    rejected.push(peer);
+                }
+            }
+            rejected
+        }
+    }
+
+    #[test]
+    fn chunks_are_spread_across_providers() {
+        let mut fixture = Fixture::new();
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for peer in peers {
+            fixture.downloader.add_provider(fixture.root, peer);
+        }
+        fixture.poll();
+
+        for peer in &peers {
+            assert_eq!(fixture.requests_to(peer).len(), MAX_IN_FLIGHT_PER_PEER);
+        }
+        let mut requested: Vec<u32> = peers.iter().flat_map(|peer| fixture.requests_to(peer)).map(|(_, index)| index).collect();
+        requested.sort();
+        assert_eq!(requested, (0..3 * MAX_IN_FLIGHT_PER_PEER as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn slow_peers_get_one_request_at_a_time() {
+        let mut fixture = Fixture::new();
+        let (fast, slow) = (PeerId::random(), PeerId::random());
+        fixture.downloader.add_provider(fixture.root, fast);
+        fixture.downloader.add_provider(fixture.root, slow);
+        fixture.downloader.record_rtt(fast, Duration::from_millis(20));
+        fixture.downloader.record_rtt(slow, Duration::from_millis(200));
+        fixture.poll();
+
+        assert_eq!(fixture.requests_to(&fast).len(), MAX_IN_FLIGHT_PER_PEER);
+        assert_eq!(fixture.requests_to(&slow).len(), MAX_IN_FLIGHT_SLOW_PEER);
+
+        // Peers on a LAN are never slow, however their RTTs compare
+        let mut fixture = Fixture::new();
+        fixture.downloader.add_provider(fixture.root, fast);
+        fixture.downloader.add_provider(fixture.root, slow);
+        fixture.downloader.record_rtt(fast, Duration::from_millis(1));
+        fixture.downloader.record_rtt(slow, Duration::from_millis(20));
+        fixture.poll();
+        assert_eq!(fixture.requests_to(&slow).len(), MAX_IN_FLIGHT_PER_PEER);
+    }
+
+    #[test]
+    fn peers_are_dropped_after_too_many_consecutive_failures() {
+        let mut fixture = Fixture::new();
+        let peer = PeerId::random();
+        fixture.downloader.add_provider(fixture.root, peer);
+
+        // Failures up to the limit, then a verified chunk, which starts the count over
+        for _ in 0..MAX_FAILED_REQUESTS {
+            fixture.poll();
+            let (request_id, _) = fixture.requests_to(&peer)[0];
+            fixture.downloader.on_failure(request_id);
+        }
+        fixture.poll();
+        let (request_id, index) = fixture.requests_to(&peer)[0];
+        fixture.answer(request_id, index);
+        assert_eq!(fixture.downloader.provider_count(&fixture.root), 1);
+
+        for _ in 0..MAX_FAILED_REQUESTS {
+            fixture.poll();
+            let (request_id, _) = fixture.requests_to(&peer)[0];
+            fixture.downloader.on_failure(request_id);
+        }
+        assert_eq!(fixture.downloader.provider_count(&fixture.root), 1);
+        assert!(fixture.rejected().is_empty());
+
+        fixture.poll();
+        fixture.fail_all(&peer);
+        assert_eq!(fixture.downloader.provider_count(&fixture.root), 0);
+        assert_eq!(fixture.rejected(), [peer]);
+    }
+
+    #[test]
+    fn chunks_in_flight_to_a_disconnected_peer_are_requested_again() {
+        let mut fixture = Fixture::new();
+        let (leaving, staying) = (PeerId::random(), PeerId::random());
+        fixture.downloader.add_provider(fixture.root, leaving);
+        fixture.downloader.add_provider(fixture.root, staying);
+        fixture.poll();
+        let orphaned: Vec<u32> = fixture.requests_to(&leaving).into_iter().map(|(_, index)| index).collect();
+        assert_eq!(orphaned.len(), MAX_IN_FLIGHT_PER_PEER);
+
+        fixture.downloader.peer_disconnected(&leaving);
+        assert!(fixture.requests_to(&leaving).is_empty());
+        assert_eq!(fixture.downloader.provider_count(&fixture.root), 1);
+
+        // Once the remaining provider has capacity again, the orphaned chunks go first
+        for (request_id, index) in fixture.requests_to(&staying) {
+            fixture.answer(request_id, index);
+        }
+        fixture.poll();
+        let requested: Vec<u32> = fixture.requests_to(&staying).into_iter().map(|(_, index)| index).collect();
+        assert_eq!(requested, orphaned);
+        assert_eq!(fixture.downloader.progress(&fixture.root), Some((4, CHUNKS)));
+    }
+}
@@ -0,0 +1,120 @@
+//! Activity of a running node, published as JSON for the HTTP event stream.
+
+use crate::downloader::DownloadEvent;
+use crate::manifest::ContentHash;
+use libp2p::{autonat, Multiaddr, PeerId};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Something that happened on the node; serialized with a `type` field naming the variant
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    /// The node listens on a new address
+    Listening { address: Multiaddr },
+    /// A peer announced itself through mDNS
+    PeerDiscovered { peer: PeerId, address: Multiaddr },
+    /// A peer's mDNS announcement expired
+    PeerExpired { peer: PeerId, address: Multiaddr },
+    /// The first connection to a peer was established
+    PeerConnected { peer: PeerId, address: Multiaddr },
+    /// The last connection to a peer was closed
+    PeerDisconnected { peer: PeerId },
+    /// A peer told us about itself through identify
+    PeerIdentified {
+        peer: PeerId,
+        agent_version: String,
+        protocol_version: String,
+    },
+    /// The reachability detected by AutoNAT changed
+    NatStatus {
+        /// `public`, `private` or `unknown`
+        status: &'static str,
+        /// Address peers on other networks reach the node at, when public
+        address: Option<Multiaddr>,
+    },
+    /// A ping round trip completed
+    Ping { peer: PeerId, rtt_ms: u64 },
+    /// A peer asked for the file list, a manifest or a chunk
+    FileRequested {
+        peer: PeerId,
+        /// `list`, `manifest` or `chunk`
+        request: &'static str,
+        /// Name or root hash of the file, if any
+        file: Option<String>,
+    },
+    /// A chunk was sent to a peer
+    ChunkServed { peer: PeerId, root: ContentHash, bytes: u64 },
+    /// A peer searched the network and was sent our matches
+    SearchReceived { peer: PeerId, matches: usize },
+    /// A file was indexed and is now shared
+    FileIndexed { name: String, root: ContentHash },
+    /// A file is no longer shared
+    FileRemoved { path: PathBuf },
+    /// A chunk of a download was verified and written
+    DownloadProgress {
+        root: ContentHash,
+        completed: u32,
+        total: u32,
+        bytes: u64,
+    },
+    /// A chunk request of a download failed or returned bad data
+    DownloadChunkFailed {
+        root: ContentHash,
+        peer: PeerId,
+        error: String,
+    },
+    /// A download completed and the file moved into place
+    DownloadCompleted { root: ContentHash, path: PathBuf },
+    /// A peer is no longer used as a source for a download
+    DownloadPeerRejected {
+        root: ContentHash,
+        peer: PeerId,
+        error: String,
+    },
+    /// A download cannot continue
+    DownloadFailed { root: ContentHash, error: String },
+}
+
+impl From<&autonat::NatStatus> for NodeEvent {
+    fn from(status: &autonat::NatStatus) -> Self {
+        NodeEvent::NatStatus {
+            status: nat_status_name(status),
+            address: match status {
+                autonat::NatStatus::Public(address) => Some(address.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl From<&DownloadEvent> for NodeEvent {
+    fn from(event: &DownloadEvent) -> Self {
+        match event.clone() {
+            DownloadEvent::Progress {
+                root,
+                completed,
+                total,
+                bytes,
+            } => NodeEvent::DownloadProgress {
+                root,
+                completed,
+                total,
+                bytes,
+            },
+            DownloadEvent::ChunkFailed { root, peer, error } => NodeEvent::DownloadChunkFailed { root, peer, error },
+            DownloadEvent::Completed { root, path } => NodeEvent::DownloadCompleted { root, path },
+            DownloadEvent::PeerRejected { root, peer, error } => NodeEvent::DownloadPeerRejected { root, peer, error },
+            DownloadEvent::Failed { root, error } => NodeEvent::DownloadFailed { root, error },
+        }
+    }
+}
+
+/// How a NAT status is named in events and in the `status` call.
+pub fn nat_status_name(status: &autonat::NatStatus) -> &'static str {
+    match status {
+        autonat::NatStatus::Public(_) => "public",
+        autonat::NatStatus::Private => "private",
+        autonat::NatStatus::Unknown => "unknown",
+    }
+}
@@ -0,0 +1,213 @@
+//! Optional local HTTP API.
+//!
+//! The REST endpoints expose the operations of the control socket, and are
+//! carried out the same way: each request is handed to the event loop as an
+//! [`RpcCall`]. `GET /events` streams [`NodeEvent`]s as server-sent events,
+//! so dashboards can follow the node's activity. The API has no
+//! authentication, so it only listens on loopback addresses, and only answers
+//! requests whose `Host` header names one: a web page that rebinds its own
+//! domain to 127.0.0.1 still sends that domain, and is turned away.
+
+use crate::events::NodeEvent;
+use crate::rpc::{self, DownloadStatusParams, RpcCall, RpcError, SearchParams};
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures::Stream;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info};
+
+/// What the request handlers share
+#[derive(Clone)]
+struct ApiState {
+    calls: UnboundedSender<RpcCall>,
+    events: broadcast::Sender<NodeEvent>,
+}
+
+/// Serves the API on `addr`, which must be a loopback address, and forwards
+/// every call to the returned receiver. Clients of the event stream receive
+/// what is sent on `events`.
+pub fn spawn_server(
+    addr: SocketAddr,
+    events: broadcast::Sender<NodeEvent>,
+) -> Result<UnboundedReceiver<RpcCall>, Box<dyn Error>> {
+    if !addr.ip().is_loopback() {
+        return Err(format!("the HTTP API only listens on loopback addresses, not {}", addr.ip()).into());
+    }
+    let listener = std::net::TcpListener::bind(addr).map_err(|e| format!("cannot listen on {}: {}", addr, e))?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let local_addr = listener.local_addr()?;
+    info!(address = %local_addr, "HTTP API listening");
+
+    let (call_tx, call_rx) = unbounded_channel();
+    let app = router(ApiState { calls: call_tx, events }, local_addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!(error = %e, "HTTP API stopped");
+        }
+    });
+    Ok(call_rx)
+}
+
+/// The API's routes, answering requests addressed to `local_addr` only.
+fn router(state: ApiState, local_addr: SocketAddr) -> Router {
+    let port = local_addr.port();
+    let hosts = vec![
+        local_addr.to_string(),
+        format!("127.0.0.1:{}", port),
+        format!("localhost:{}", port),
+        format!("[::1]:{}", port),
+    ];
+    Router::new()
+        .route("/peers", get(peers).post(dial))
+        .route("/shares", get(shares).post(add_shares))
+        .route("/downloads", get(downloads).post(start_download))
+        .route("/search", get(search))
+        .route("/status", get(status))
+        .route("/events", get(event_stream))
+        .with_state(state)
+        .layer(middleware::from_fn_with_state(Arc::new(hosts), check_host))
+}
+
+/// Rejects requests whose `Host` is not one of `hosts`, which protects the
+/// API from web pages using DNS rebinding.
+async fn check_host(State(hosts): State<Arc<Vec<String>>>, request: Request, next: Next) -> Response {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().authority().map(|authority| authority.as_str()));
+    if host.is_some_and(|host| hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))) {
+        next.run(request).await
+    } else {
+        (StatusCode::FORBIDDEN, Json(json!({ "error": "unexpected Host header" }))).into_response()
+    }
+}
+
+/// `GET /peers`: the connected peers.
+async fn peers(State(state): State<ApiState>) -> Response {
+    call(&state, "peers_list", Value::Null).await
+}
+
+/// `POST /peers` with `{"address": ...}`: dials a peer.
+async fn dial(State(state): State<ApiState>, Json(params): Json<Value>) -> Response {
+    call(&state, "dial", params).await
+}
+
+/// `GET /shares`: the shared files.
+async fn shares(State(state): State<ApiState>) -> Response {
+    call(&state, "files_list", Value::Null).await
+}
+
+/// `POST /shares` with `{"paths": [...]}`: shares more paths.
+async fn add_shares(State(state): State<ApiState>, Json(params): Json<Value>) -> Response {
+    call(&state, "share_add", params).await
+}
+
+/// `GET /downloads`, optionally with `?root=`: the download history and progress.
+async fn downloads(State(state): State<ApiState>, Query(params): Query<DownloadStatusParams>) -> Response {
+    call(&state, "download_status", json!(params)).await
+}
+
+/// `POST /downloads` with `{"peer": ..., "file": ...}`: starts a download.
+async fn start_download(State(state): State<ApiState>, Json(params): Json<Value>) -> Response {
+    call(&state, "download_start", params).await
+}
+
+/// `GET /search` with the criteria as query parameters: searches the network.
+async fn search(State(state): State<ApiState>, Query(params): Query<SearchParams>) -> Response {
+    call(&state, "search", json!(params)).await
+}
+
+/// `GET /status`: the node's identity, addresses and reachability.
+async fn status(State(state): State<ApiState>) -> Response {
+    call(&state, "status", Value::Null).await
+}
+
+/// `GET /events`: the node's activity as server-sent events, one JSON object each.
+async fn event_stream(State(state): State<ApiState>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = futures::stream::unfold(state.events.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((Event::default().json_data(&event), receiver)),
+                // A client too slow to keep up misses events rather than holding up the node
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Carries out a call through the event loop and turns its result into a response.
+async fn call(state: &ApiState, method: &str, params: Value) -> Response {
+    match rpc::forward(&state.calls, method, params).await {
+        Ok(result) => Json(result).into_response(),
+        Err(error) => {
+            let status = match error.code {
+                RpcError::INVALID_PARAMS => StatusCode::BAD_REQUEST,
+                RpcError::FAILED => StatusCode::UNPROCESSABLE_ENTITY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, Json(json!({ "error": error.message }))).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    /// The API on `127.0.0.1:8080`, with an event loop that answers every call with `"ok"`.
+    fn app() -> Router {
+        let (calls, mut received) = unbounded_channel::<RpcCall>();
+        tokio::spawn(async move {
+            while let Some(call) = received.recv().await {
+                let _ = call.reply.send(Ok(json!("ok")));
+            }
+        });
+        let state = ApiState {
+            calls,
+            events: broadcast::channel(1).0,
+        };
+        router(state, SocketAddr::from(([127, 0, 0, 1], 8080)))
+    }
+
+    async fn status_with_host(host: Option<&str>) -> StatusCode {
+        let mut request = Request::get("/status");
+        if let Some(host) = host {
+            request = request.header(header::HOST, host);
+        }
+        app().oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn loopback_hosts_are_answered() {
+        for host in ["127.0.0.1:8080", "localhost:8080", "LOCALHOST:8080", "[::1]:8080"] {
+            assert_eq!(status_with_host(Some(host)).await, StatusCode::OK, "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_hosts_are_refused() {
+        for host in [Some("attacker.example:8080"), Some("127.0.0.1:9090"), Some("localhost"), None] {
+            assert_eq!(status_with_host(host).await, StatusCode::FORBIDDEN, "{:?}", host);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Persistent node identity.
+//!
+//! The node keypair is stored protobuf-encoded (the standard libp2p key format)
+//! in a file inside the data directory, so the PeerId stays the same across
+//! restarts. The file is created on first run and must only be readable by the
+//! owner.
+
+use libp2p::identity::Keypair;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// File name of the identity key inside the data directory
+pub const IDENTITY_FILE: &str = "identity.key";
+
+/// Returns the default data directory, e.g. `~/.local/share/rust-p2p-share` on Linux.
+pub fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rust-p2p-share")
+}
+
+/// Loads the keypair stored at `path`, generating and saving a new Ed25519
+/// keypair if the file does not exist yet.
+pub fn load_or_generate(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    if path.exists() {
+        return load(path);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    write_key_file(path, &keypair)?;
+    info!(path = %path.display(), "Generated new identity");
+    Ok(keypair)
+}
+
+/// Loads a protobuf-encoded keypair, refusing files that other users can read.
+pub fn load(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    check_permissions(path)?;
+    let bytes = fs::read(path)?;
+    let keypair = Keypair::from_protobuf_encoding(&bytes)
+        .map_err(|e| format!("invalid identity file {}: {}", path.display(), e))?;
+    Ok(keypair)
+}
+
+/// Imports an existing libp2p key from `source` and stores it at `dest`.
+///
+/// Importing the key that is already in place is a no-op; replacing a
+/// different identity is refused so a node cannot lose its PeerId by accident.
+pub fn import(source: &Path, dest: &Path) -> Result<Keypair, Box<dyn Error>> {
+    let bytes = fs::read(source)?;
+    let keypair = Keypair::from_protobuf_encoding(&bytes)
+        .map_err(|e| format!("{} is not a protobuf-encoded libp2p key: {}", source.display(), e))?;
+
+    if dest.exists() {
+        let existing = load(dest)?;
+        if existing.public() != keypair.public() {
+            return Err(format!(
+                "refusing to overwrite existing identity {} ({}); move it away first",
+                dest.display(),
+                existing.public().to_peer_id()
+            )
+            .into());
+        }
+        return Ok(existing);
+    }
+
+    write_key_file(dest, &keypair)?;
+    info!(path = %dest.display(), "Imported identity");
+    Ok(keypair)
+}
+
+/// Writes the keypair to a new file that only the owner can read and write.
+fn write_key_file(path: &Path, keypair: &Keypair) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        create_private_dir(parent)?;
+    }
+
+    let bytes = keypair.to_protobuf_encoding()?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Creates a directory (and its parents) accessible only by the owner.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    if dir.as_os_str().is_empty() || dir.exists() {
+        return Ok(());
+    }
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+/// Rejects key files that are accessible by the group or other users.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "identity file {} has insecure permissions {:o}; run `chmod 600` on it",
+            path.display(),
+            mode & 0o777
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `keypair` to `path` the way another libp2p tool would.
+    fn export(keypair: &Keypair, path: &Path) {
+        fs::write(path, keypair.to_protobuf_encoding().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn generated_keys_are_private_and_stable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node").join(IDENTITY_FILE);
+        let keypair = load_or_generate(&path).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        assert_eq!(load_or_generate(&path).unwrap().public(), keypair.public());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readable_keys_are_rejected() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(IDENTITY_FILE);
+        export(&Keypair::generate_ed25519(), &path);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert!(check_permissions(&path).is_err());
+        assert!(load(&path).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(load(&path).is_ok());
+    }
+
+    #[test]
+    fn import_refuses_to_replace_another_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join(IDENTITY_FILE);
+        let existing = load_or_generate(&dest).unwrap();
+
+        let other = dir.path().join("other.key");
+        export(&Keypair::generate_ed25519(), &other);
+        assert!(import(&other, &dest).is_err());
+        assert_eq!(load(&dest).unwrap().public(), existing.public());
+
+        // Importing the key already in place changes nothing
+        let same = dir.path().join("same.key");
+        export(&existing, &same);
+        assert_eq!(import(&same, &dest).unwrap().public(), existing.public());
+    }
+
+    #[test]
+    fn import_stores_a_new_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("exported.key");
+        let keypair = Keypair::generate_ed25519();
+        export(&keypair, &source);
+
+        let dest = dir.path().join("node").join(IDENTITY_FILE);
+        assert_eq!(import(&source, &dest).unwrap().public(), keypair.public());
+        assert_eq!(load(&dest).unwrap().public(), keypair.public());
+    }
+}
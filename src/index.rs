@@ -0,0 +1,334 @@
+//! Index of shared files and their manifests.
+//!
+//! A background indexer thread scans the shared paths, hashes new or modified
+//! files and watches the filesystem for changes, sending [`IndexUpdate`]s to
+//! the event loop, which applies them to its [`ShareIndex`]. The share list
+//! itself is watched too, so `share` and `acl` take effect on a running node. Manifests
+//! are persisted in the [`Store`], so files unchanged since the last run are
+//! not hashed again.
+
+use crate::access::{AccessRule, AccessRules};
+use crate::manifest::{ContentHash, Manifest};
+use crate::protocol::FileEntry;
+use crate::shares::{self, SharedFile, Shares};
+use crate::store::Store;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::warn;
+
+/// Filesystem events closer together than this are handled as one batch
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Longest a batch keeps collecting events, so a steady stream of changes is still handled
+const MAX_BATCH_DELAY: Duration = Duration::from_secs(5);
+
+/// A shared file together with its manifest
+#[derive(Debug, Clone)]
+pub struct IndexedFile {
+    /// Location on the local filesystem
+    pub path: PathBuf,
+    /// Modification time when the manifest was computed
+    pub modified: SystemTime,
+    /// MIME type guessed from the file extension
+    pub mime: String,
+    /// Name, size, chunk hashes and root hash of the file
+    pub manifest: Manifest,
+}
+
+impl IndexedFile {
+    /// The file as advertised to other peers.
+    pub fn entry(&self) -> FileEntry {
+        FileEntry {
+            name: self.manifest.name.clone(),
+            size: self.manifest.size,
+            root: self.manifest.root,
+            modified: self
+                .modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            mime: self.mime.clone(),
+        }
+    }
+}
+
+/// A change to the set of shared files, sent by the indexer thread
+#[derive(Debug)]
+pub enum IndexUpdate {
+    /// A file was added or its contents changed
+    Indexed(IndexedFile),
+    /// A file is no longer shared
+    Removed(PathBuf),
+    /// The access rules of the share list were loaded or changed
+    Rules(AccessRules),
+}
+
+/// The manifests of every file covered by the share list, and who may access them
+#[derive(Default)]
+pub struct ShareIndex {
+    files: HashMap<PathBuf, IndexedFile>,
+    rules: AccessRules,
+}
+
+impl ShareIndex {
+    /// Creates an empty index, to be filled by the updates of [`spawn_indexer`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies an update from the indexer thread, returning the entry it replaced or removed.
+    pub fn apply(&mut self, update: IndexUpdate) -> Option<IndexedFile> {
+        match update {
+            IndexUpdate::Indexed(file) => self.files.insert(file.path.clone(), file),
+            IndexUpdate::Removed(path) => self.files.remove(&path),
+            IndexUpdate::Rules(rules) => {
+                self.rules = rules;
+                None
+            }
+        }
+    }
+
+    /// The access rule that applies to `file`.
+    pub fn rule_for(&self, file: &IndexedFile) -> &AccessRule {
+        self.rules.rule_for(&file.path)
+    }
+
+    /// Iterates over all indexed files.
+    pub fn files(&self) -> impl Iterator<Item = &IndexedFile> {
+        self.files.values()
+    }
+
+    /// Iterates over the files with the given shared name or hex form of a root
+    /// hash; several files can have the same contents.
+    pub fn find_all<'a>(&'a self, name_or_root: &str) -> impl Iterator<Item = &'a IndexedFile> {
+        let root = name_or_root.parse::<ContentHash>().ok();
+        let name = name_or_root.to_string();
+        self.files
+            .values()
+            .filter(move |file| file.manifest.name == name || Some(file.manifest.root) == root)
+    }
+
+    /// Looks up a file by root hash.
+    pub fn by_root(&self, root: &ContentHash) -> Option<&IndexedFile> {
+        self.with_root(root).next()
+    }
+
+    /// Iterates over the files with root hash `root`.
+    pub fn with_root<'a>(&'a self, root: &ContentHash) -> impl Iterator<Item = &'a IndexedFile> {
+        let root = *root;
+        self.files.values().filter(move |file| file.manifest.root == root)
+    }
+}
+
+/// Starts the indexer thread for the share list stored in `data_dir`.
+///
+/// The thread performs an initial scan and then keeps watching the shared
+/// paths; it stops when the returned receiver is dropped.
+pub fn spawn_indexer(data_dir: &Path) -> Result<UnboundedReceiver<IndexUpdate>, Box<dyn Error>> {
+    fs::create_dir_all(data_dir)?;
+    let (event_tx, event_rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(event_tx)?;
+    watcher.watch(data_dir, RecursiveMode::NonRecursive)?;
+
+    let store = Store::open(data_dir)?;
+
+    // The rules go first, so no cached file is served before its rule is known
+    let (update_tx, update_rx) = unbounded_channel();
+    let _ = update_tx.send(IndexUpdate::Rules(Shares::load(data_dir)?.rules));
+    let mut indexer = Indexer {
+        data_dir: data_dir.to_path_buf(),
+        shares: Shares::default(),
+        known: HashMap::new(),
+        watcher,
+        store,
+        updates: update_tx,
+    };
+    indexer.load_cached()?;
+    thread::Builder::new()
+        .name("indexer".to_string())
+        .spawn(move || {
+            indexer.reload_shares();
+            indexer.run(event_rx);
+        })?;
+    Ok(update_rx)
+}
+
+/// What the indexer last reported for a file
+struct KnownFile {
+    name: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// State of the indexer thread
+struct Indexer {
+    data_dir: PathBuf,
+    shares: Shares,
+    known: HashMap<PathBuf, KnownFile>,
+    watcher: RecommendedWatcher,
+    store: Store,
+    updates: UnboundedSender<IndexUpdate>,
+}
+
+impl Indexer {
+    /// Publishes the files indexed during the previous run, before any rescan.
+    fn load_cached(&mut self) -> Result<(), Box<dyn Error>> {
+        for file in self.store.files()? {
+            self.known.insert(
+                file.path.clone(),
+                KnownFile {
+                    name: file.manifest.name.clone(),
+                    size: file.manifest.size,
+                    modified: file.modified,
+                },
+            );
+            let _ = self.updates.send(IndexUpdate::Indexed(file));
+        }
+        Ok(())
+    }
+
+    /// Handles filesystem events until the event loop goes away.
+    fn run(mut self, events: mpsc::Receiver<notify::Result<notify::Event>>) {
+        while let Ok(first) = events.recv() {
+            // Collect the burst of events a single copy or save usually produces
+            let mut batch = vec![first];
+            let deadline = Instant::now() + MAX_BATCH_DELAY;
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                let Ok(event) = events.recv_timeout(left.min(DEBOUNCE)) else {
+                    break;
+                };
+                batch.push(event);
+            }
+
+            // Of the data directory only the share list matters; the store writes there constantly
+            let shares_path = shares::shares_path(&self.data_dir);
+            let mut changed: Vec<PathBuf> = batch
+                .into_iter()
+                .filter_map(Result::ok)
+                .flat_map(|event| event.paths)
+                .filter(|path| *path == shares_path || !path.starts_with(&self.data_dir))
+                .collect();
+            changed.sort();
+            changed.dedup();
+
+            if changed.contains(&shares_path) {
+                self.reload_shares();
+            } else {
+                for path in changed {
+                    self.rescan(&path);
+                }
+            }
+            if self.updates.is_closed() {
+                return;
+            }
+        }
+    }
+
+    /// Re-reads the share list, updates the watched paths and rescans everything.
+    fn reload_shares(&mut self) {
+        let shares = match Shares::load(&self.data_dir) {
+            Ok(shares) => shares,
+            Err(e) => {
+                warn!(error = %e, "Cannot read share list");
+                return;
+            }
+        };
+        for old in &self.shares.paths {
+            let _ = self.watcher.unwatch(old);
+        }
+        for path in &shares.paths {
+            if let Err(e) = self.watcher.watch(path, RecursiveMode::Recursive) {
+                warn!(path = %path.display(), error = %e, "Cannot watch shared path");
+            }
+        }
+        let _ = self.updates.send(IndexUpdate::Rules(shares.rules.clone()));
+        self.shares = shares;
+
+        let files = self.shares.files();
+        let removed: Vec<PathBuf> = self
+            .known
+            .keys()
+            .filter(|known| !files.iter().any(|file| &file.path == *known))
+            .cloned()
+            .collect();
+        for path in removed {
+            self.remove(path);
+        }
+        for file in files {
+            self.index(file);
+        }
+    }
+
+    /// Re-indexes the shared files at or below `path`, removing those that disappeared.
+    fn rescan(&mut self, path: &Path) {
+        let files = self.shares.files_under(path);
+        let removed: Vec<PathBuf> = self
+            .known
+            .keys()
+            .filter(|known| known.starts_with(path) && !files.iter().any(|file| &file.path == *known))
+            .cloned()
+            .collect();
+        for known in removed {
+            self.remove(known);
+        }
+        for file in files {
+            self.index(file);
+        }
+    }
+
+    /// Hashes `file` unless it is unchanged since it was last indexed.
+    fn index(&mut self, file: SharedFile) {
+        let Ok(modified) = fs::metadata(&file.path).and_then(|metadata| metadata.modified()) else {
+            return;
+        };
+        if let Some(known) = self.known.get(&file.path) {
+            if known.modified == modified && known.size == file.size && known.name == file.name {
+                return;
+            }
+        }
+
+        let manifest = match Manifest::from_file(&file.path, file.name.clone()) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!(path = %file.path.display(), error = %e, "Skipping file");
+                return;
+            }
+        };
+        let mime = mime_guess::from_path(&file.path)
+            .first_or_octet_stream()
+            .essence_str()
+            .to_string();
+        self.known.insert(
+            file.path.clone(),
+            KnownFile {
+                name: file.name,
+                size: file.size,
+                modified,
+            },
+        );
+        let indexed = IndexedFile {
+            path: file.path,
+            modified,
+            mime,
+            manifest,
+        };
+        if let Err(e) = self.store.save_file(&indexed) {
+            warn!(path = %indexed.path.display(), error = %e, "Cannot store index entry");
+        }
+        let _ = self.updates.send(IndexUpdate::Indexed(indexed));
+    }
+
+    fn remove(&mut self, path: PathBuf) {
+        self.known.remove(&path);
+        if let Err(e) = self.store.remove_file(&path) {
+            warn!(path = %path.display(), error = %e, "Cannot remove index entry");
+        }
+        let _ = self.updates.send(IndexUpdate::Removed(path));
+    }
+}
@@ -0,0 +1,59 @@
+//! Log output through `tracing`.
+//!
+//! Messages go to stderr, so the output of commands such as `list` or `search`
+//! stays clean on stdout. Which messages are shown is controlled by
+//! `--log-filter` (or `RUST_LOG`), which takes `tracing` filter directives, or
+//! else by `--log-level`, which applies to this application alone. The
+//! terminal interface owns the screen, so it has messages appended to a file
+//! instead.
+
+use crate::cli::{Cli, LogFormat, LogLevel};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global subscriber configured by the command-line options,
+/// writing to `file` if given and to stderr otherwise.
+pub fn init(cli: &Cli, file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let filter = match &cli.log_filter {
+        Some(directives) => EnvFilter::try_new(directives).map_err(|e| format!("invalid log filter: {}", e))?,
+        None => EnvFilter::new(format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level_name(cli.log_level))),
+    };
+    let (writer, ansi) = match file {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("cannot open log file {}: {}", path.display(), e))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal()),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    let result = match cli.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    result.map_err(|e| e as Box<dyn Error>)
+}
+
+fn level_name(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "error",
+        LogLevel::Warn => "warn",
+        LogLevel::Info => "info",
+        LogLevel::Debug => "debug",
+        LogLevel::Trace => "trace",
+    }
+}
@@ -1,137 +1,381 @@
 //! A P2P file sharing application built with libp2p
-//! 
-//! This application demonstrates basic peer-to-peer networking capabilities using libp2p,
-//! including peer discovery, identification, and ping functionality.
+//!
+//! Peers find each other with mDNS on the local network and through a Kademlia
+//! DHT beyond it, search each other's files over gossipsub and download them
+//! as chunks verified against a manifest, from several peers at once. `run`
+//! and `tui` start the node; the other subcommands manage its shares and access
+//! lists, act as short-lived clients of other peers, or call the running node
+//! through its control socket.
 
+mod access;
+mod cli;
+mod client;
+mod download;
+mod downloader;
+mod events;
+mod http;
+mod identity;
+mod index;
+mod logging;
+mod manifest;
+mod metrics;
+mod network;
+mod node;
+mod protocol;
+mod rpc;
+mod search;
+mod shares;
+mod static_peers;
+mod store;
+mod tui;
+
+use access::{AccessRule, PeerAccess, PeerLists};
+use clap::Parser;
+use cli::{Cli, Command};
+use client::Client;
 use futures::StreamExt;
-use libp2p::{
-    identify,
-    identity::Keypair,
-    mdns,
-    noise,
-    ping,
-    swarm::{NetworkBehaviour, Swarm, SwarmEvent},
-    tcp,
-    yamux,
-    PeerId,
-    Transport,
-};
+use libp2p::{identity::Keypair, mdns, swarm::SwarmEvent, Multiaddr, PeerId};
+use network::MyBehaviourEvent;
+use search::SearchQuery;
+use shares::Shares;
+use store::Store;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
-use tokio;
-
-/// Represents the network behavior of our P2P node.
-/// This struct combines multiple behaviors:
-/// - Identify: Helps peers exchange identification information
-/// - Ping: Allows checking connectivity with peers
-/// - MDNS: Enables automatic peer discovery on local networks
-#[derive(NetworkBehaviour)]
-#[behaviour(out_event = "MyBehaviourEvent")]
-struct MyBehaviour {
-    identify: identify::Behaviour,
-    ping: ping::Behaviour,
-    mdns: mdns::async_io::Behaviour,
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let data_dir = cli.data_dir.clone().unwrap_or_else(identity::default_data_dir);
+    let log_file = matches!(cli.command, Some(Command::Tui)).then(|| data_dir.join(tui::LOG_FILE));
+    logging::init(&cli, log_file.as_deref())?;
+
+    // Load the persistent keypair, creating it on first run
+    let identity_path = cli
+        .identity
+        .clone()
+        .unwrap_or_else(|| data_dir.join(identity::IDENTITY_FILE));
+
+    match cli.command.as_ref().unwrap_or(&Command::Run) {
+        Command::Run => {
+            let local_key = identity::load_or_generate(&identity_path)?;
+            node::run(&cli, &local_key, &data_dir, None).await
+        }
+        Command::Tui => {
+            let local_key = identity::load_or_generate(&identity_path)?;
+            tui::run(&cli, &local_key, &data_dir).await
+        }
+        Command::Share { paths } => share_paths(&data_dir, paths),
+        Command::List { peer: None } => list_local(&data_dir),
+        Command::List { peer: Some(peer) } => {
+            let local_key = client_key(&cli, &data_dir, &identity_path)?;
+            let access = PeerAccess::new(&cli, &data_dir)?;
+            let mut client = Client::connect(&cli, &local_key, access, *peer).await?;
+            for entry in client.list().await? {
+                println!("{}\t{}\t{}\t{}", entry.root, entry.size, entry.mime, entry.name);
+            }
+            Ok(())
+        }
+        Command::Get {
+            peer,
+            name,
+            output,
+            force,
+        } => {
+            let local_key = client_key(&cli, &data_dir, &identity_path)?;
+            let access = PeerAccess::new(&cli, &data_dir)?;
+            let mut client = Client::connect(&cli, &local_key, access, *peer).await?;
+            let saved = client.download(&data_dir, name, output.as_deref(), *force).await?;
+            println!("Saved {} to {}", name, saved.display());
+            Ok(())
+        }
+        Command::Search {
+            name,
+            extension,
+            min_size,
+            max_size,
+            hash,
+            timeout,
+        } => {
+            let local_key = client_key(&cli, &data_dir, &identity_path)?;
+            let access = PeerAccess::new(&cli, &data_dir)?;
+            let query = SearchQuery {
+                id: 0,
+                name: name.clone(),
+                extension: extension.clone(),
+                min_size: *min_size,
+                max_size: *max_size,
+                root: *hash,
+            };
+            for (peer, entry) in search::search(&cli, &local_key, &access, query, Duration::from_secs(*timeout)).await? {
+                println!("{}\t{}\t{}\t{}\t{}", peer, entry.root, entry.size, entry.mime, entry.name);
+            }
+            Ok(())
+        }
+        Command::Peers { known: true, .. } => list_known_peers(&data_dir),
+        Command::Downloads => list_downloads(&data_dir),
+        Command::Peers { timeout, .. } => {
+            let local_key = client_key(&cli, &data_dir, &identity_path)?;
+            let access = PeerAccess::new(&cli, &data_dir)?;
+            list_peers(&cli, &local_key, &access, Duration::from_secs(*timeout)).await
+        }
+        Command::Trust { peers, remove } => {
+            edit_peer_list(&data_dir, peers, *remove, |lists| &mut lists.trusted, "trusted")
+        }
+        Command::Block { peers, remove } => {
+            edit_peer_list(&data_dir, peers, *remove, |lists| &mut lists.blocked, "blocked")
+        }
+        Command::Group { name: None, .. } => list_groups(&data_dir),
+        Command::Group {
+            name: Some(name),
+            peers,
+            remove,
+        } => edit_group(&data_dir, name, peers, *remove),
+        Command::Acl { path: None, .. } => list_rules(&data_dir),
+        Command::Acl {
+            path: Some(path),
+            public,
+            peers,
+            groups,
+        } => {
+            let rule = if *public {
+                Some(AccessRule::Public)
+            } else if peers.is_empty() && groups.is_empty() {
+                None
+            } else {
+                Some(AccessRule::Restricted {
+                    peers: peers.iter().copied().collect(),
+                    groups: groups.iter().cloned().collect(),
+                })
+            };
+            set_rule(&data_dir, path, rule)
+        }
+        Command::Rpc { method, params } => {
+            let socket = control_socket(&cli, &data_dir);
+            let params = match params {
+                Some(params) => serde_json::from_str(params).map_err(|e| format!("invalid parameters: {}", e))?,
+                None => serde_json::Value::Null,
+            };
+            let result = rpc::call(&socket, method, params).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+            Ok(())
+        }
+        Command::Id { import } => {
+            let local_key = match import {
+                Some(source) => identity::import(source, &identity_path)?,
+                None => identity::load_or_generate(&identity_path)?,
+            };
+            println!("{}", local_key.public().to_peer_id());
+            Ok(())
+        }
+    }
 }
 
-/// Represents all possible events that can be emitted by our network behavior.
-/// This enum combines events from all our behaviors (Identify, Ping, MDNS).
-#[derive(Debug)]
-enum MyBehaviourEvent {
-    Identify(identify::Event),
-    Ping(ping::Event),
-    Mdns(mdns::Event),
+/// Path of the control socket of the node using `data_dir`.
+fn control_socket(cli: &Cli, data_dir: &Path) -> PathBuf {
+    cli.control_socket
+        .clone()
+        .unwrap_or_else(|| data_dir.join(rpc::CONTROL_SOCKET))
 }
 
-// Implementation of From traits to convert specific behavior events into our custom event type
-impl From<identify::Event> for MyBehaviourEvent {
-    fn from(event: identify::Event) -> Self {
-        MyBehaviourEvent::Identify(event)
+/// Keypair for the short-lived swarm of `list`, `get`, `search` or `peers`.
+///
+/// Peers address a node by its PeerId only, so while the node is running with
+/// the same identity it would receive what is sent back to the command, such
+/// as search results. A throwaway key is used then.
+fn client_key(cli: &Cli, data_dir: &Path, identity_path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    if rpc::is_listening(&control_socket(cli, data_dir)) {
+        info!("The node is running; connecting under a temporary PeerId");
+        return Ok(Keypair::generate_ed25519());
     }
+    identity::load_or_generate(identity_path)
 }
 
-impl From<ping::Event> for MyBehaviourEvent {
-    fn from(event: ping::Event) -> Self {
-        MyBehaviourEvent::Ping(event)
+/// Runs mDNS discovery for `timeout` and prints every peer found with its addresses.
+async fn list_peers(
+    cli: &Cli,
+    local_key: &Keypair,
+    access: &PeerAccess,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut swarm = network::build_swarm(local_key, cli, access)?;
+    network::listen(&mut swarm, cli)?;
+
+    let mut peers: BTreeMap<PeerId, Vec<Multiaddr>> = BTreeMap::new();
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(found))) = event {
+                    for (peer_id, addr) in found {
+                        let addrs = peers.entry(peer_id).or_default();
+                        if !addrs.contains(&addr) {
+                            addrs.push(addr);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for (peer_id, addrs) in peers {
+        let addrs: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+        println!("{}\t{}", peer_id, addrs.join(" "));
     }
+    Ok(())
 }
 
-impl From<mdns::Event> for MyBehaviourEvent {
-    fn from(event: mdns::Event) -> Self {
-        MyBehaviourEvent::Mdns(event)
+/// Prints the peers recorded in the store, most recently seen first.
+fn list_known_peers(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let store = Store::open(data_dir)?;
+    for peer in store.known_peers()? {
+        let addrs: Vec<String> = peer.addresses.iter().map(ToString::to_string).collect();
+        println!("{}", peer.peer_id);
+        println!("  last seen:  {}", peer.last_seen);
+        println!("  agent:      {}", peer.agent_version.as_deref().unwrap_or("-"));
+        println!("  protocol:   {}", peer.protocol_version.as_deref().unwrap_or("-"));
+        println!("  protocols:  {}", peer.protocols.join(", "));
+        println!("  addresses:  {}", addrs.join(" "));
     }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Generate a random Ed25519 keypair for secure communication
-    let local_key = Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
-
-    // Set up the noise protocol for authentication
-    let auth_config = noise::Config::new(&local_key).expect("signing libp2p-noise static keypair failed");
-    
-    // Create a transport layer with the following stack:
-    // - TCP as the underlying transport
-    // - Upgrade to secure channel using noise protocol
-    // - Multiplex multiple substreams using yamux
-    let transport = tcp::async_io::Transport::new(tcp::Config::default())
-        .upgrade(libp2p::core::upgrade::Version::V1Lazy)
-        .authenticate(auth_config)
-        .multiplex(yamux::Config::default())
-        .boxed();
-
-    // Create a Swarm to manage peers and network events
-    let mut swarm = {
-        // Set up the identify protocol
-        let identify = identify::Behaviour::new(identify::Config::new(
-            "rust-p2p-example/1.0.0".to_string(),
-            local_key.public(),
-        ));
-        
-        // Set up the ping protocol
-        let ping = ping::Behaviour::new(ping::Config::new());
-        
-        // Set up mDNS for peer discovery
-        let mdns = mdns::async_io::Behaviour::new(mdns::Config::default(), local_peer_id)?;
-        
-        // Combine all protocols into a single behavior
-        let behaviour = MyBehaviour {
-            identify,
-            ping,
-            mdns,
-        };
-        
-        // Create the swarm using tokio as the executor
-        let config = libp2p::swarm::Config::with_tokio_executor();
-        Swarm::new(transport, behaviour, local_peer_id, config)
-    };
+/// Prints the download history, most recent first.
+fn list_downloads(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let store = Store::open(data_dir)?;
+    for record in store.downloads()? {
+        let finished_at = record.finished_at.map_or("-".to_string(), |at| at.to_string());
+        println!(
+            "{:?}\t{}\t{}\t{}\t{}\t{}\t{}{}",
+            record.status,
+            record.started_at,
+            finished_at,
+            record.root,
+            record.size,
+            record.name,
+            record.output.display(),
+            record.error.map(|e| format!("\t{}", e)).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
 
-    // Listen on all interfaces with a random port
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+/// Adds `paths` to the persisted share list.
+fn share_paths(data_dir: &Path, paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let mut shares = Shares::load(data_dir)?;
+    for path in paths {
+        if shares.add(path)? {
+            println!("Sharing {}", path.display());
+        } else {
+            println!("Already shared: {}", path.display());
+        }
+    }
+    shares.save(data_dir)
+}
 
-    // Main event loop
-    loop {
-        match swarm.select_next_some().await {
-            // New listening address has been established
-            SwarmEvent::NewListenAddr { address, .. } => {
-                println!("Listening on {:?}", address);
-            }
-            // New peer discovered through mDNS
-            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
-                for (peer_id, addr) in peers {
-                    println!("Discovered peer {} with addr {}", peer_id, addr);
-                }
-            }
-            // Received an identify event
-            SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => {
-                println!("Identify event: {:?}", event);
+/// Adds `peers` to, or with `remove` removes them from, the list picked by
+/// `list`, or prints the list when no peers are given.
+fn edit_peer_list(
+    data_dir: &Path,
+    peers: &[PeerId],
+    remove: bool,
+    list: impl Fn(&mut PeerLists) -> &mut BTreeSet<PeerId>,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut lists = PeerLists::load(data_dir)?;
+    if peers.is_empty() {
+        for peer in list(&mut lists).iter() {
+            println!("{}", peer);
+        }
+        return Ok(());
+    }
+    for peer in peers {
+        if remove {
+            if list(&mut lists).remove(peer) {
+                println!("No longer {}: {}", name, peer);
+            } else {
+                println!("Not {}: {}", name, peer);
             }
-            // Received a ping event
-            SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => {
-                println!("Ping event: {:?}", event);
+        } else if list(&mut lists).insert(*peer) {
+            println!("Now {}: {}", name, peer);
+        } else {
+            println!("Already {}: {}", name, peer);
+        }
+    }
+    lists.save(data_dir)
+}
+
+/// Prints every group with its members.
+fn list_groups(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let lists = PeerLists::load(data_dir)?;
+    for (name, members) in &lists.groups {
+        let members: Vec<String> = members.iter().map(ToString::to_string).collect();
+        println!("{}\t{}", name, members.join(" "));
+    }
+    Ok(())
+}
+
+/// Adds `peers` to, or with `remove` removes them from, group `name`, or
+/// prints its members when no peers are given. Empty groups are deleted.
+fn edit_group(data_dir: &Path, name: &str, peers: &[PeerId], remove: bool) -> Result<(), Box<dyn Error>> {
+    let mut lists = PeerLists::load(data_dir)?;
+    if peers.is_empty() {
+        for peer in lists.groups.get(name).into_iter().flatten() {
+            println!("{}", peer);
+        }
+        return Ok(());
+    }
+    let members = lists.groups.entry(name.to_string()).or_default();
+    for peer in peers {
+        if remove {
+            if members.remove(peer) {
+                println!("Removed {} from {}", peer, name);
+            } else {
+                println!("Not in {}: {}", name, peer);
             }
-            // Ignore all other events
-            _ => {}
+        } else if members.insert(*peer) {
+            println!("Added {} to {}", peer, name);
+        } else {
+            println!("Already in {}: {}", name, peer);
         }
     }
+    if members.is_empty() {
+        lists.groups.remove(name);
+    }
+    lists.save(data_dir)
+}
+
+/// Prints every access rule of the share list.
+fn list_rules(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let shares = Shares::load(data_dir)?;
+    for (path, rule) in shares.rules.iter() {
+        println!("{}\t{}", path.display(), rule);
+    }
+    Ok(())
+}
+
+/// Sets the access rule of `path`, or prints the rule that applies to it when
+/// `rule` is `None`.
+fn set_rule(data_dir: &Path, path: &Path, rule: Option<AccessRule>) -> Result<(), Box<dyn Error>> {
+    let mut shares = Shares::load(data_dir)?;
+    let Some(rule) = rule else {
+        println!("{}", shares.rules.rule_for(&path.canonicalize()?));
+        return Ok(());
+    };
+    let canonical = shares.set_rule(path, rule.clone())?;
+    println!("{}: {}", canonical.display(), rule);
+    shares.save(data_dir)
+}
+
+/// Prints every file currently covered by the local share list.
+fn list_local(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let shares = Shares::load(data_dir)?;
+    for file in shares.files() {
+        println!("{}\t{}", file.size, file.name);
+    }
+    Ok(())
 }
@@ -0,0 +1,271 @@
+//! Content addressing of shared files.
+//!
+//! Files are split into fixed-size chunks, each identified by its BLAKE3 hash.
+//! A [`Manifest`] lists the chunk hashes of a file together with a root hash
+//! derived from them, which identifies the file contents on the network.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Size of every chunk except possibly the last one of a file
+pub const CHUNK_SIZE: u32 = 256 * 1024;
+
+/// A BLAKE3 hash, written as 64 hex characters
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    /// Hashes `data`.
+    pub fn of(data: &[u8]) -> Self {
+        ContentHash(*blake3::hash(data).as_bytes())
+    }
+
+    /// The raw hash bytes.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(blake3::Hash::from(self.0).to_hex().as_str())
+    }
+}
+
+impl fmt::Debug for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ContentHash({})", self)
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = blake3::HexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ContentHash(*blake3::Hash::from_hex(s)?.as_bytes()))
+    }
+}
+
+impl Serialize for ContentHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Describes a file as a list of chunk hashes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Name the file is shared under
+    pub name: String,
+    /// Total size in bytes
+    pub size: u64,
+    /// Size of every chunk except possibly the last one
+    pub chunk_size: u32,
+    /// Hash of every chunk, in file order
+    pub chunks: Vec<ContentHash>,
+    /// Hash over the size, chunk size and chunk hashes; identifies the contents
+    pub root: ContentHash,
+}
+
+impl Manifest {
+    /// Reads and hashes the file at `path`, sharing it under `name`.
+    pub fn from_file(path: &Path, name: String) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+        let mut chunks = Vec::new();
+        let mut size = 0u64;
+        loop {
+            let len = read_full(&mut file, &mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            chunks.push(ContentHash::of(&buffer[..len]));
+            size += len as u64;
+        }
+
+        let root = Self::root_of(size, CHUNK_SIZE, &chunks);
+        Ok(Manifest {
+            name,
+            size,
+            chunk_size: CHUNK_SIZE,
+            chunks,
+            root,
+        })
+    }
+
+    /// Computes the root hash for the given file layout.
+    pub fn root_of(size: u64, chunk_size: u32, chunks: &[ContentHash]) -> ContentHash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&size.to_le_bytes());
+        hasher.update(&chunk_size.to_le_bytes());
+        for chunk in chunks {
+            hasher.update(&chunk.0);
+        }
+        ContentHash(*hasher.finalize().as_bytes())
+    }
+
+    /// Checks that the manifest is self-consistent: the chunk count matches the
+    /// size and the root hash matches the chunk hashes.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        if self.chunk_size == 0 {
+            return Err("manifest has a zero chunk size".into());
+        }
+        let expected_chunks = self.size.div_ceil(self.chunk_size as u64);
+        if self.chunks.len() as u64 != expected_chunks {
+            return Err(format!(
+                "manifest lists {} chunks but a {} byte file has {}",
+                self.chunks.len(),
+                self.size,
+                expected_chunks
+            )
+            .into());
+        }
+        if Self::root_of(self.size, self.chunk_size, &self.chunks) != self.root {
+            return Err("manifest root hash does not match its chunks".into());
+        }
+        Ok(())
+    }
+
+    /// Number of chunks in the file.
+    pub fn chunk_count(&self) -> u32 {
+        self.chunks.len() as u32
+    }
+
+    /// Byte offset of chunk `index` within the file.
+    pub fn chunk_offset(&self, index: u32) -> u64 {
+        index as u64 * self.chunk_size as u64
+    }
+
+    /// Length in bytes of chunk `index`.
+    pub fn chunk_len(&self, index: u32) -> u64 {
+        let offset = self.chunk_offset(index);
+        (self.size - offset).min(self.chunk_size as u64)
+    }
+
+    /// Checks that `data` is the expected contents of chunk `index`.
+    pub fn verify_chunk(&self, index: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let Some(expected) = self.chunks.get(index as usize) else {
+            return Err(format!("chunk {} is out of range", index).into());
+        };
+        if data.len() as u64 != self.chunk_len(index) {
+            return Err(format!(
+                "chunk {} has {} bytes, expected {}",
+                index,
+                data.len(),
+                self.chunk_len(index)
+            )
+            .into());
+        }
+        if ContentHash::of(data) != *expected {
+            return Err(format!("chunk {} does not match its hash", index).into());
+        }
+        Ok(())
+    }
+
+    /// Reads chunk `index` from the file at `path` and checks it against the manifest.
+    pub fn read_chunk(&self, path: &Path, index: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        if index >= self.chunk_count() {
+            return Err(format!("chunk {} is out of range", index).into());
+        }
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(self.chunk_offset(index)))?;
+        let mut data = vec![0u8; self.chunk_len(index) as usize];
+        file.read_exact(&mut data)?;
+        self.verify_chunk(index, &data)?;
+        Ok(data)
+    }
+}
+
+/// Fills `buffer` as far as possible, returning fewer bytes only at end of file.
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A manifest of a file spanning two full chunks and a partial one.
+    fn sample() -> (tempfile::TempDir, Vec<u8>, Manifest) {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..CHUNK_SIZE as usize * 2 + 1000).map(|i| (i % 251) as u8).collect();
+        let path = dir.path().join("sample.bin");
+        fs::write(&path, &data).unwrap();
+        let manifest = Manifest::from_file(&path, "sample.bin".to_string()).unwrap();
+        (dir, data, manifest)
+    }
+
+    #[test]
+    fn manifest_of_a_file_verifies() {
+        let (_dir, data, manifest) = sample();
+        assert_eq!(manifest.size, data.len() as u64);
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_len(2), 1000);
+        manifest.verify().unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_inconsistent_manifests() {
+        let (_dir, _data, manifest) = sample();
+
+        let mut swapped = manifest.clone();
+        swapped.chunks.swap(0, 1);
+        assert!(swapped.verify().is_err());
+
+        let mut truncated = manifest.clone();
+        truncated.chunks.pop();
+        truncated.root = Manifest::root_of(truncated.size, truncated.chunk_size, &truncated.chunks);
+        assert!(truncated.verify().is_err());
+
+        let mut zero = manifest;
+        zero.chunk_size = 0;
+        assert!(zero.verify().is_err());
+    }
+
+    #[test]
+    fn verify_chunk_accepts_only_the_original_data() {
+        let (_dir, data, manifest) = sample();
+        let chunk = &data[..CHUNK_SIZE as usize];
+        manifest.verify_chunk(0, chunk).unwrap();
+
+        let mut tampered = chunk.to_vec();
+        tampered[100] ^= 1;
+        assert!(manifest.verify_chunk(0, &tampered).is_err());
+        // Right contents, wrong position
+        assert!(manifest.verify_chunk(1, chunk).is_err());
+        assert!(manifest.verify_chunk(2, &data[..999]).is_err());
+        assert!(manifest.verify_chunk(3, &data[..1000]).is_err());
+    }
+
+    #[test]
+    fn read_chunk_rejects_a_tampered_file() {
+        let (dir, mut data, manifest) = sample();
+        let path = dir.path().join("sample.bin");
+        assert_eq!(manifest.read_chunk(&path, 2).unwrap(), &data[CHUNK_SIZE as usize * 2..]);
+
+        data[CHUNK_SIZE as usize + 5] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        manifest.read_chunk(&path, 0).unwrap();
+        assert!(manifest.read_chunk(&path, 1).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Prometheus metrics of a running node.
+//!
+//! libp2p records its own metrics for the swarm and for identify, ping,
+//! Kademlia, gossipsub, relay and DCUtR; the node adds transfer counters and
+//! a ping round-trip histogram of its own. Everything is served in the
+//! OpenMetrics text format on `/metrics`.
+
+use crate::downloader::DownloadEvent;
+use crate::network::MyBehaviourEvent;
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use libp2p::metrics::Recorder;
+use libp2p::swarm::SwarmEvent;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::{
+    counter::Counter,
+    gauge::Gauge,
+    histogram::{exponential_buckets, Histogram},
+};
+use prometheus_client::registry::Registry;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Content type of the OpenMetrics text format
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The node's metrics, registered in a [`Registry`]
+pub struct Metrics {
+    libp2p: libp2p::metrics::Metrics,
+    bytes_uploaded: Counter,
+    bytes_downloaded: Counter,
+    chunks_verified: Counter,
+    chunks_failed: Counter,
+    active_downloads: Gauge,
+    active_uploads: Gauge,
+    ping_rtt: Histogram,
+}
+
+impl Metrics {
+    /// Creates the metrics, registering them in `registry`.
+    pub fn new(registry: &mut Registry) -> Self {
+        let libp2p = libp2p::metrics::Metrics::new(registry);
+        let registry = registry.sub_registry_with_prefix("p2p_share");
+
+        let bytes_uploaded = Counter::default();
+        registry.register("bytes_uploaded", "Bytes of chunks sent to other peers", bytes_uploaded.clone());
+        let bytes_downloaded = Counter::default();
+        registry.register("bytes_downloaded", "Bytes of chunks downloaded and verified", bytes_downloaded.clone());
+        let chunks_verified = Counter::default();
+        registry.register("chunks_verified", "Downloaded chunks that matched the manifest", chunks_verified.clone());
+        let chunks_failed = Counter::default();
+        registry.register(
+            "chunks_failed",
+            "Chunk requests that failed or returned data not matching the manifest",
+            chunks_failed.clone(),
+        );
+        let active_downloads = Gauge::default();
+        registry.register("active_downloads", "Downloads in progress", active_downloads.clone());
+        let active_uploads = Gauge::default();
+        registry.register("active_uploads", "Chunks being sent to other peers", active_uploads.clone());
+        // From 1 ms to about 8 s
+        let ping_rtt = Histogram::new(exponential_buckets(0.001, 2.0, 14));
+        registry.register("ping_rtt_seconds", "Ping round-trip times", ping_rtt.clone());
+
+        Metrics {
+            libp2p,
+            bytes_uploaded,
+            bytes_downloaded,
+            chunks_verified,
+            chunks_failed,
+            active_downloads,
+            active_uploads,
+            ping_rtt,
+        }
+    }
+
+    /// Records a swarm event, and the protocol event it carries, in the libp2p metrics.
+    pub fn record(&self, event: &SwarmEvent<MyBehaviourEvent>) {
+        self.libp2p.record(event);
+        match event {
+            SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => self.libp2p.record(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => {
+                self.libp2p.record(event);
+                if let Ok(rtt) = event.result {
+                    self.ping_rtt.observe(rtt.as_secs_f64());
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kad(event)) => self.libp2p.record(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(event)) => self.libp2p.record(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(event)) => self.libp2p.record(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(event)) => self.libp2p.record(event),
+            _ => {}
+        }
+    }
+
+    /// Counts a chunk served to another peer.
+    pub fn chunk_served(&self, bytes: usize) {
+        self.bytes_uploaded.inc_by(bytes as u64);
+    }
+
+    /// Counts the verified and failed chunks of a download.
+    pub fn record_download(&self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::Progress { bytes, .. } => {
+                self.chunks_verified.inc();
+                self.bytes_downloaded.inc_by(*bytes);
+            }
+            DownloadEvent::ChunkFailed { .. } => {
+                self.chunks_failed.inc();
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets the number of downloads in progress.
+    pub fn set_active_downloads(&self, count: usize) {
+        self.active_downloads.set(count as i64);
+    }
+
+    /// Sets the number of chunks being sent.
+    pub fn set_active_uploads(&self, count: usize) {
+        self.active_uploads.set(count as i64);
+    }
+}
+
+/// Serves the metrics registered in `registry` on `127.0.0.1:<port>`.
+pub fn spawn_server(port: u16, registry: Registry) -> Result<(), Box<dyn Error>> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let listener = std::net::TcpListener::bind(addr).map_err(|e| format!("cannot listen on {}: {}", addr, e))?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    info!(address = %listener.local_addr()?, "Serving metrics");
+
+    let app = Router::new()
+        .route("/metrics", get(serve_metrics))
+        .with_state(Arc::new(registry));
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!(error = %e, "Metrics server stopped");
+        }
+    });
+    Ok(())
+}
+
+/// `GET /metrics`: every metric in the OpenMetrics text format.
+async fn serve_metrics(State(registry): State<Arc<Registry>>) -> impl IntoResponse {
+    let mut body = String::new();
+    // Writing to a String cannot fail
+    let _ = encode(&mut body, &registry);
+    ([(header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)], body)
+}
@@ -0,0 +1,409 @@
+//! Network behaviour and swarm construction for the P2P node.
+
+use crate::access::PeerAccess;
+use crate::cli::Cli;
+use crate::manifest::ContentHash;
+use crate::protocol::{FileRequest, FileResponse, FILE_PROTOCOL};
+use crate::search;
+use futures::future::{self, Either};
+use futures::TryFutureExt;
+use libp2p::{
+    allow_block_list::{self, AllowedPeers, BlockedPeers},
+    autonat,
+    connection_limits::{self, ConnectionLimits},
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{ListenerId, OptionalTransport},
+    },
+    dcutr,
+    gossipsub,
+    identify,
+    identity::Keypair,
+    kad::{self, store::{MemoryStore, MemoryStoreConfig}},
+    mdns,
+    memory_connection_limits,
+    multiaddr::Protocol,
+    noise,
+    ping,
+    pnet::{PnetConfig, PnetError, PreSharedKey},
+    quic,
+    relay,
+    request_response::{self, ProtocolSupport},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, Swarm},
+    tcp,
+    upnp,
+    yamux,
+    Multiaddr,
+    PeerId,
+    StreamProtocol,
+    Transport,
+};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a connection without active streams is kept open
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Protocol name of the Kademlia DHT, kept separate from other libp2p networks
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-share/kad/1.0.0");
+
+/// How many root hashes this node can announce as a provider
+const MAX_PROVIDED_KEYS: usize = 65536;
+
+/// The request/response behaviour carrying the file-sharing protocol
+pub type FileShareBehaviour = request_response::cbor::Behaviour<FileRequest, FileResponse>;
+
+/// Represents the network behavior of our P2P node.
+/// This struct combines multiple behaviors:
+/// - Blocked: Refuses connections with blocked peers
+/// - Allowed: Refuses connections with untrusted peers, in private mode
+/// - ConnectionLimits: Refuses connections beyond the configured counts
+/// - MemoryLimits: Refuses connections while the process uses too much memory
+/// - Identify: Helps peers exchange identification information
+/// - Ping: Allows checking connectivity with peers
+/// - MDNS: Enables automatic peer discovery on local networks
+/// - FileShare: Lists and transfers shared files between peers
+/// - Gossipsub: Spreads search queries to every peer on the search topic
+/// - Kad: Finds peers and providers of content beyond the local network
+/// - Relay: Relays connections for peers behind NAT, when enabled
+/// - RelayClient: Reserves slots on relays and connects through them
+/// - AutoNat: Asks peers to dial back to find out whether this node is reachable
+/// - Dcutr: Upgrades relayed connections to direct ones through hole punching
+/// - Upnp: Asks the router to forward the listening ports, when enabled
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "MyBehaviourEvent")]
+pub struct MyBehaviour {
+    pub blocked: allow_block_list::Behaviour<BlockedPeers>,
+    pub allowed: Toggle<allow_block_list::Behaviour<AllowedPeers>>,
+    pub connection_limits: connection_limits::Behaviour,
+    pub memory_limits: memory_connection_limits::Behaviour,
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
+    pub mdns: mdns::async_io::Behaviour,
+    pub file_share: FileShareBehaviour,
+    pub gossipsub: gossipsub::Behaviour,
+    pub kad: kad::Behaviour<MemoryStore>,
+    pub relay: Toggle<relay::Behaviour>,
+    pub relay_client: relay::client::Behaviour,
+    pub autonat: autonat::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+}
+
+/// Represents all possible events that can be emitted by our network behavior.
+/// This enum combines events from all our behaviors (Identify, Ping, MDNS, FileShare,
+/// Gossipsub, Kad, Relay, RelayClient, AutoNat, Dcutr, Upnp); the peer lists and
+/// the connection and memory limits emit none.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum MyBehaviourEvent {
+    Identify(identify::Event),
+    Ping(ping::Event),
+    Mdns(mdns::Event),
+    FileShare(request_response::Event<FileRequest, FileResponse>),
+    Gossipsub(gossipsub::Event),
+    Kad(kad::Event),
+    Relay(relay::Event),
+    RelayClient(relay::client::Event),
+    AutoNat(autonat::Event),
+    Dcutr(dcutr::Event),
+    Upnp(upnp::Event),
+}
+
+// Implementation of From traits to convert specific behavior events into our custom event type
+impl From<void::Void> for MyBehaviourEvent {
+    fn from(event: void::Void) -> Self {
+        void::unreachable(event)
+    }
+}
+
+impl From<identify::Event> for MyBehaviourEvent {
+    fn from(event: identify::Event) -> Self {
+        MyBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<ping::Event> for MyBehaviourEvent {
+    fn from(event: ping::Event) -> Self {
+        MyBehaviourEvent::Ping(event)
+    }
+}
+
+impl From<mdns::Event> for MyBehaviourEvent {
+    fn from(event: mdns::Event) -> Self {
+        MyBehaviourEvent::Mdns(event)
+    }
+}
+
+impl From<request_response::Event<FileRequest, FileResponse>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<FileRequest, FileResponse>) -> Self {
+        MyBehaviourEvent::FileShare(event)
+    }
+}
+
+impl From<gossipsub::Event> for MyBehaviourEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        MyBehaviourEvent::Gossipsub(event)
+    }
+}
+
+impl From<kad::Event> for MyBehaviourEvent {
+    fn from(event: kad::Event) -> Self {
+        MyBehaviourEvent::Kad(event)
+    }
+}
+
+impl From<relay::Event> for MyBehaviourEvent {
+    fn from(event: relay::Event) -> Self {
+        MyBehaviourEvent::Relay(event)
+    }
+}
+
+impl From<relay::client::Event> for MyBehaviourEvent {
+    fn from(event: relay::client::Event) -> Self {
+        MyBehaviourEvent::RelayClient(event)
+    }
+}
+
+impl From<autonat::Event> for MyBehaviourEvent {
+    fn from(event: autonat::Event) -> Self {
+        MyBehaviourEvent::AutoNat(event)
+    }
+}
+
+impl From<dcutr::Event> for MyBehaviourEvent {
+    fn from(event: dcutr::Event) -> Self {
+        MyBehaviourEvent::Dcutr(event)
+    }
+}
+
+impl From<upnp::Event> for MyBehaviourEvent {
+    fn from(event: upnp::Event) -> Self {
+        MyBehaviourEvent::Upnp(event)
+    }
+}
+
+/// Builds the swarm for `local_key`, configured from the command-line options
+/// and connecting only to the peers `access` allows.
+pub fn build_swarm(local_key: &Keypair, cli: &Cli, access: &PeerAccess) -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
+    let local_peer_id = PeerId::from(local_key.public());
+
+    // Set up the noise protocol for authentication
+    let auth_config = noise::Config::new(local_key).expect("signing libp2p-noise static keypair failed");
+
+    // The relay client comes with a transport that dials and listens through relays
+    let (relay_transport, relay_client) = relay::client::new(local_peer_id);
+
+    // With a swarm key, only peers holding the same key get past the first handshake
+    let swarm_key = cli.swarm_key.as_deref().map(load_swarm_key).transpose()?;
+
+    // Create a transport layer with the following stack:
+    // - TCP, or a circuit through a relay, as the underlying transport
+    // - Encrypt with the swarm key, when one is given
+    // - Upgrade to secure channel using noise protocol
+    // - Multiplex multiple substreams using yamux
+    let tcp_transport = relay_transport
+        .or_transport(tcp::async_io::Transport::new(tcp::Config::default()))
+        .and_then(move |socket, _| match swarm_key {
+            Some(key) => Either::Left(PnetConfig::new(key).handshake(socket).map_ok(Either::Left)),
+            None => Either::Right(future::ok::<_, PnetError>(Either::Right(socket))),
+        })
+        .upgrade(libp2p::core::upgrade::Version::V1Lazy)
+        .authenticate(auth_config)
+        .multiplex(yamux::Config::default());
+
+    // QUIC brings its own encryption and stream multiplexing; its handshake
+    // cannot be wrapped with the swarm key, so it is left out when one is given
+    let quic_transport = match swarm_key {
+        Some(_) => OptionalTransport::none(),
+        None => OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(local_key))),
+    };
+
+    // Accept both, dialing whichever transport the address asks for
+    let transport = tcp_transport
+        .or_transport(quic_transport)
+        .map(|output, _| match output {
+            Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+            Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+        })
+        .boxed();
+
+    // Set up the identify protocol
+    let identify = identify::Behaviour::new(identify::Config::new(
+        cli.protocol_version.clone(),
+        local_key.public(),
+    ));
+
+    // Refuse connections with blocked peers and, in private mode, with untrusted ones
+    let mut blocked = allow_block_list::Behaviour::default();
+    for peer in &access.blocked {
+        blocked.block_peer(*peer);
+    }
+    let allowed = access
+        .private
+        .then(|| {
+            let mut allowed = allow_block_list::Behaviour::default();
+            for peer in &access.trusted {
+                allowed.allow_peer(*peer);
+            }
+            allowed
+        })
+        .into();
+
+    // Refuse connections beyond the configured limits, or while memory runs short
+    let connection_limits = connection_limits::Behaviour::new(
+        ConnectionLimits::default()
+            .with_max_established_incoming(Some(cli.max_inbound_connections))
+            .with_max_established_outgoing(Some(cli.max_outbound_connections))
+            .with_max_established_per_peer(Some(cli.max_connections_per_peer)),
+    );
+    let memory_limits =
+        memory_connection_limits::Behaviour::with_max_percentage(f64::from(cli.max_memory_percent) / 100.0);
+
+    // Set up the ping protocol
+    let ping = ping::Behaviour::new(ping::Config::new());
+
+    // Set up mDNS for peer discovery
+    let mdns = mdns::async_io::Behaviour::new(mdns::Config::default(), local_peer_id)?;
+
+    // Set up the file-sharing request/response protocol, bounding how many
+    // transfers run at once on each connection
+    let file_share = FileShareBehaviour::new(
+        [(FILE_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default().with_max_concurrent_streams(cli.max_transfer_streams),
+    );
+
+    // Set up gossipsub for search queries; messages are signed so that
+    // answers can be sent back to the node that asked
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .build()?;
+    let mut gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(local_key.clone()),
+        gossipsub_config,
+    )?;
+    gossipsub.subscribe(&search::search_topic())?;
+
+    // Set up the Kademlia DHT for routing and provider records
+    let mut kad_config = kad::Config::default();
+    kad_config.set_protocol_names(vec![KAD_PROTOCOL]);
+    let kad_store = MemoryStore::with_config(
+        local_peer_id,
+        MemoryStoreConfig {
+            max_provided_keys: MAX_PROVIDED_KEYS,
+            ..Default::default()
+        },
+    );
+    let kad = kad::Behaviour::with_config(local_peer_id, kad_store, kad_config);
+
+    // Relay connections for other peers only when asked to
+    let relay = cli
+        .relay_server
+        .then(|| relay::Behaviour::new(local_peer_id, relay_config(cli)))
+        .into();
+
+    // Set up AutoNAT to learn whether this node is publicly reachable,
+    // and DCUtR to connect directly to peers first reached through a relay
+    let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
+    let dcutr = dcutr::Behaviour::new(local_peer_id);
+
+    // Map the listening ports on the router only when asked to
+    let upnp = cli.upnp.then(upnp::tokio::Behaviour::default).into();
+
+    // Combine all protocols into a single behavior
+    let behaviour = MyBehaviour {
+        blocked,
+        allowed,
+        connection_limits,
+        memory_limits,
+        identify,
+        ping,
+        mdns,
+        file_share,
+        gossipsub,
+        kad,
+        relay,
+        relay_client,
+        autonat,
+        dcutr,
+        upnp,
+    };
+
+    // Create the swarm using tokio as the executor,
+    // and keep idle connections open long enough to be reused for requests
+    let config = libp2p::swarm::Config::with_tokio_executor()
+        .with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT);
+    Ok(Swarm::new(transport, behaviour, local_peer_id, config))
+}
+
+/// Limits of the relay server, from the command-line options.
+fn relay_config(cli: &Cli) -> relay::Config {
+    relay::Config {
+        max_reservations: cli.relay_max_reservations,
+        max_circuits: cli.relay_max_circuits,
+        max_circuits_per_peer: cli.relay_max_circuits_per_peer,
+        max_circuit_duration: Duration::from_secs(cli.relay_max_circuit_duration),
+        max_circuit_bytes: cli.relay_max_circuit_bytes,
+        ..Default::default()
+    }
+}
+
+/// Reads a swarm key file in the usual `/key/swarm/psk/1.0.0/` format.
+fn load_swarm_key(path: &Path) -> Result<PreSharedKey, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read swarm key {}: {}", path.display(), e))?;
+    text.parse()
+        .map_err(|e| format!("invalid swarm key {}: {}", path.display(), e).into())
+}
+
+/// Starts listening on the `--listen` addresses and returns the listeners.
+///
+/// QUIC addresses are skipped when a swarm key is given, since the transport
+/// then has no QUIC support.
+pub fn listen(swarm: &mut Swarm<MyBehaviour>, cli: &Cli) -> Result<Vec<ListenerId>, Box<dyn Error>> {
+    let mut listeners = Vec::new();
+    for addr in &cli.listen_addrs {
+        if cli.swarm_key.is_some() && addr.iter().any(|protocol| protocol == Protocol::QuicV1) {
+            continue;
+        }
+        listeners.push(swarm.listen_on(addr.clone())?);
+    }
+    Ok(listeners)
+}
+
+/// Returns the peer id an address ends with, as required for bootstrap peers and relays.
+pub fn peer_id_of(addr: &Multiaddr) -> Result<PeerId, Box<dyn Error>> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Ok(peer_id),
+        _ => Err(format!("address {} does not end with /p2p/<peer id>", addr).into()),
+    }
+}
+
+/// Adds the bootstrap peers to the DHT routing table and starts a bootstrap query.
+///
+/// Every address must end with `/p2p/<peer id>`.
+pub fn bootstrap(swarm: &mut Swarm<MyBehaviour>, addrs: &[Multiaddr]) -> Result<(), Box<dyn Error>> {
+    for addr in addrs {
+        let peer_id = peer_id_of(addr)?;
+        swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+    }
+    if !addrs.is_empty() {
+        swarm.behaviour_mut().kad.bootstrap()?;
+    }
+    Ok(())
+}
+
+/// Adds the listen addresses a peer reported through identify to the DHT, if it takes part in it.
+pub fn add_identified_peer(behaviour: &mut MyBehaviour, peer_id: &PeerId, info: &identify::Info) {
+    if info.protocols.contains(&KAD_PROTOCOL) {
+        for addr in &info.listen_addrs {
+            behaviour.kad.add_address(peer_id, addr.clone());
+        }
+    }
+}
+
+/// The DHT key under which the providers of `root` are announced.
+pub fn provider_key(root: &ContentHash) -> kad::RecordKey {
+    kad::RecordKey::new(root.as_bytes())
+}
@@ -0,0 +1,1372 @@
+//! The long-running node: serves shared files, reports network events and
+//! answers the calls of the control socket and the HTTP API.
+
+use crate::access::PeerAccess;
+use crate::cli::Cli;
+use crate::download::{self, Download};
+use crate::downloader::{DownloadEvent, Downloader};
+use crate::events::{self, NodeEvent};
+use crate::http;
+use crate::index::{self, IndexUpdate, IndexedFile, ShareIndex};
+use crate::manifest::ContentHash;
+use crate::metrics::{self, Metrics};
+use crate::network::{self, MyBehaviour, MyBehaviourEvent};
+use crate::protocol::{FileEntry, FileRequest, FileResponse, LIST_PAGE_SIZE};
+use crate::rpc::{self, RpcCall, RpcError};
+use crate::search::{self, SearchQuery, MAX_SEARCH_RESULTS};
+use crate::shares::Shares;
+use crate::static_peers::StaticPeers;
+use crate::store::Store;
+use futures::StreamExt;
+use libp2p::{
+    autonat,
+    core::transport::ListenerId,
+    dcutr,
+    gossipsub,
+    identify,
+    identity::Keypair,
+    kad::{self, store::MemoryStore},
+    mdns,
+    multiaddr::Protocol,
+    relay,
+    request_response::{self, OutboundRequestId},
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        DialError, ListenError, Swarm, SwarmEvent,
+    },
+    upnp,
+    Multiaddr,
+    PeerId,
+};
+use prometheus_client::registry::Registry;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, debug_span, error, info, info_span, warn, Span};
+
+/// How often the node refreshes its DHT routing table and looks for providers
+/// of unfinished downloads
+const DHT_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait before reserving a slot again on a relay whose connection was lost
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Events kept for each client of the event stream that has not received them yet
+pub const EVENT_BUFFER: usize = 1024;
+
+/// How long the node keeps running after a shutdown request so the router can
+/// remove its port mappings
+const UPNP_RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A user interface running in the same process as the node
+pub struct Frontend {
+    /// Calls of the interface, carried out like those of the control socket;
+    /// the node stops once the sending half is dropped
+    pub calls: UnboundedReceiver<RpcCall>,
+    /// Where the node publishes its activity
+    pub events: broadcast::Sender<NodeEvent>,
+}
+
+/// Runs the node's main event loop until the process receives Ctrl-C or
+/// SIGTERM, or the user quits the `frontend`.
+pub async fn run(
+    cli: &Cli,
+    local_key: &Keypair,
+    data_dir: &Path,
+    frontend: Option<Frontend>,
+) -> Result<(), Box<dyn Error>> {
+    let local_peer_id = PeerId::from(local_key.public());
+    info!(peer = %local_peer_id, "Local peer id");
+
+    // The indexer thread hashes shared files and reports changes as they happen
+    let mut index = ShareIndex::new();
+    let mut index_updates = index::spawn_indexer(data_dir)?;
+
+    // Peers and downloads are recorded in the store; unfinished downloads
+    // continue once a peer holding their content is found
+    let store = Rc::new(Store::open(data_dir)?);
+    let mut downloader = Downloader::load(store.clone())?;
+
+    // Blocked peers, and untrusted ones in private mode, are refused
+    let access = PeerAccess::new(cli, data_dir)?;
+    let mut swarm = network::build_swarm(local_key, cli, &access)?;
+    let listeners = network::listen(&mut swarm, cli)?;
+
+    // Reserve a slot on every relay, so peers can connect through it
+    let mut relay_listeners = HashMap::new();
+    for relay in &cli.relays {
+        relay_listeners.insert(reserve_relay_slot(&mut swarm, relay)?, relay.clone());
+    }
+    let mut lost_relays = Vec::new();
+    let relay_retry = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(relay_retry);
+
+    // The node answers DHT queries from other peers, and looks for providers
+    // of its downloads beyond the local network
+    swarm.behaviour_mut().kad.set_mode(Some(kad::Mode::Server));
+    network::bootstrap(&mut swarm, &cli.bootstrap_addrs)?;
+    // Static and bootstrap peers are dialed now and whenever the connection is lost
+    let mut static_peers = StaticPeers::new(cli.static_peers.iter().chain(&cli.bootstrap_addrs))?;
+
+    let mut dht_refresh = tokio::time::interval_at(
+        Instant::now() + DHT_REFRESH_INTERVAL,
+        DHT_REFRESH_INTERVAL,
+    );
+
+    // Other tools control the node through JSON-RPC calls on a Unix socket
+    let control_socket = cli
+        .control_socket
+        .clone()
+        .unwrap_or_else(|| data_dir.join(rpc::CONTROL_SOCKET));
+    let mut rpc_calls = rpc::spawn_server(&control_socket)?;
+    let mut pending = PendingCalls::default();
+
+    // Dashboards use the optional HTTP API, which also streams the node's activity
+    let has_frontend = frontend.is_some();
+    let (events, mut frontend_calls) = match frontend {
+        Some(frontend) => (frontend.events, frontend.calls),
+        None => (broadcast::channel(EVENT_BUFFER).0, unbounded_channel().1),
+    };
+    let mut http_calls = match cli.http {
+        Some(addr) => http::spawn_server(addr, events.clone())?,
+        None => unbounded_channel().1,
+    };
+    // Metrics are always recorded, and served when a port is given
+    let mut registry = Registry::default();
+    let metrics = Metrics::new(&mut registry);
+    if let Some(port) = cli.metrics_port {
+        metrics::spawn_server(port, registry)?;
+    }
+    // Chunks answered but not yet sent, for the active uploads gauge
+    let mut uploads = HashSet::new();
+
+    // Events are dropped while nobody follows the stream
+    let publish = |event: NodeEvent| {
+        let _ = events.send(event);
+    };
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Main event loop, until the process is asked to stop
+    loop {
+        downloader.poll(&mut swarm.behaviour_mut().file_share);
+        while let Some(event) = downloader.next_event() {
+            metrics.record_download(&event);
+            publish(NodeEvent::from(&event));
+            report_download_event(event);
+        }
+
+        metrics.set_active_downloads(downloader.roots().count());
+        metrics.set_active_uploads(uploads.len());
+
+        let next_dial = static_peers.next_dial();
+        let next_search = pending.next_search_deadline();
+
+        let event = tokio::select! {
+            Some(update) = index_updates.recv() => {
+                match &update {
+                    IndexUpdate::Indexed(file) => {
+                        debug!(name = %file.manifest.name, root = %file.manifest.root, "Indexed file");
+                        publish(NodeEvent::FileIndexed {
+                            name: file.manifest.name.clone(),
+                            root: file.manifest.root,
+                        });
+                    }
+                    IndexUpdate::Removed(path) => {
+                        debug!(path = %path.display(), "No longer sharing file");
+                        publish(NodeEvent::FileRemoved { path: path.clone() });
+                    }
+                    IndexUpdate::Rules(_) => debug!("Loaded access rules"),
+                }
+                let announced = match &update {
+                    IndexUpdate::Indexed(file) => Some(file.manifest.root),
+                    IndexUpdate::Removed(_) | IndexUpdate::Rules(_) => None,
+                };
+                let previous = index.apply(update);
+                let kad = &mut swarm.behaviour_mut().kad;
+                if let Some(root) = announced {
+                    announce(kad, &root);
+                }
+                // Stop announcing content that is no longer shared under any name
+                if let Some(previous) = previous {
+                    let root = previous.manifest.root;
+                    if Some(root) != announced && index.by_root(&root).is_none() {
+                        kad.stop_providing(&network::provider_key(&root));
+                    }
+                }
+                continue;
+            }
+            _ = dht_refresh.tick() => {
+                // Fails only while the routing table is empty, in which case there is nothing to refresh
+                let _ = swarm.behaviour_mut().kad.bootstrap();
+                find_providers(&mut swarm, &downloader);
+                continue;
+            }
+            _ = &mut relay_retry, if !lost_relays.is_empty() => {
+                for relay in lost_relays.drain(..) {
+                    match reserve_relay_slot(&mut swarm, &relay) {
+                        Ok(listener_id) => {
+                            relay_listeners.insert(listener_id, relay);
+                        }
+                        Err(e) => warn!(%relay, error = %e, "Cannot reserve a slot on relay"),
+                    }
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(next_dial.unwrap_or_else(Instant::now)), if next_dial.is_some() => {
+                static_peers.dial_due(&mut swarm);
+                continue;
+            }
+            Some(call) = rpc_calls.recv() => {
+                handle_call(call, &mut swarm, &index, &mut downloader, &store, data_dir, &mut pending);
+                continue;
+            }
+            Some(call) = http_calls.recv() => {
+                handle_call(call, &mut swarm, &index, &mut downloader, &store, data_dir, &mut pending);
+                continue;
+            }
+            call = frontend_calls.recv(), if has_frontend => {
+                // The interface drops its end when the user quits
+                let Some(call) = call else { break };
+                handle_call(call, &mut swarm, &index, &mut downloader, &store, data_dir, &mut pending);
+                continue;
+            }
+            _ = tokio::time::sleep_until(next_search.unwrap_or_else(Instant::now)), if next_search.is_some() => {
+                pending.finish_searches();
+                continue;
+            }
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+            event = swarm.select_next_some() => event,
+        };
+
+        metrics.record(&event);
+        // Messages about a peer or connection are logged within its span
+        let _span = event_span(&event).entered();
+        match event {
+            // New listening address has been established
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!(%address, "Listening");
+                publish(NodeEvent::Listening { address: address.clone() });
+                // A relay is only useful if it can tell its clients where to reach it,
+                // so a relay server is assumed to be reachable on its own addresses
+                if cli.relay_server && is_shareable_address(&address) {
+                    swarm.add_external_address(address);
+                }
+            }
+            // The connection to a relay was lost; reserve a slot again later
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                if let Some(relay) = relay_listeners.remove(&listener_id) {
+                    match reason {
+                        Ok(()) => info!(%relay, "Reservation on relay closed"),
+                        Err(e) => warn!(%relay, error = %e, "Reservation on relay closed"),
+                    }
+                    lost_relays.push(relay);
+                    relay_retry.as_mut().reset(Instant::now() + RELAY_RETRY_INTERVAL);
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(event)) => match event {
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal,
+                    ..
+                } => {
+                    if renewal {
+                        debug!(relay = %relay_peer_id, "Renewed the reservation on relay");
+                    } else {
+                        info!(relay = %relay_peer_id, "Reserved a slot on relay");
+                    }
+                }
+                relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                    info!(peer = %src_peer_id, "Peer connected through a relay; trying to connect directly");
+                }
+                relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                    info!(relay = %relay_peer_id, "Connected to a peer through relay");
+                }
+            },
+            // Whether this node can be reached by peers on other networks
+            SwarmEvent::Behaviour(MyBehaviourEvent::AutoNat(autonat::Event::StatusChanged { new, .. })) => {
+                report_nat_status(&new, !cli.relays.is_empty());
+                publish(NodeEvent::from(&new));
+            }
+            // Outcome of hole punching on a relayed connection
+            SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => match result {
+                Ok(_) => info!(peer = %remote_peer_id, "Connected directly"),
+                Err(e) => info!(peer = %remote_peer_id, error = %e, "Cannot connect directly, staying on the relay"),
+            },
+            // Port mappings requested from the router
+            SwarmEvent::Behaviour(MyBehaviourEvent::Upnp(event)) => match event {
+                upnp::Event::NewExternalAddr(address) => {
+                    info!(%address, "Router forwards address to this node");
+                }
+                upnp::Event::ExpiredExternalAddr(address) => {
+                    info!(%address, "Router no longer forwards address");
+                }
+                upnp::Event::GatewayNotFound => warn!("No UPnP gateway found; ports are not forwarded"),
+                upnp::Event::NonRoutableGateway => {
+                    warn!("The UPnP gateway is not on the public network; ports are not forwarded")
+                }
+            },
+            // Reservations and circuits served as a relay
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(event)) => match event {
+                relay::Event::ReservationReqAccepted { src_peer_id, renewed: false } => {
+                    info!(peer = %src_peer_id, "Accepted relay reservation");
+                }
+                relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                    info!(src = %src_peer_id, dst = %dst_peer_id, "Relaying a circuit");
+                }
+                relay::Event::ReservationReqDenied { src_peer_id } => {
+                    warn!(peer = %src_peer_id, "Denied relay reservation: limit reached");
+                }
+                relay::Event::CircuitReqDenied { src_peer_id, dst_peer_id } => {
+                    warn!(src = %src_peer_id, dst = %dst_peer_id, "Denied a circuit");
+                }
+                event => debug!(?event, "Relay event"),
+            },
+            // New peer discovered through mDNS
+            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                for (peer_id, addr) in peers.into_iter().filter(|(peer_id, _)| access.allows(peer_id)) {
+                    info!(peer = %peer_id, address = %addr, "Discovered peer");
+                    publish(NodeEvent::PeerDiscovered {
+                        peer: peer_id,
+                        address: addr.clone(),
+                    });
+                    record(store.peer_seen(&peer_id, Some(&addr)));
+                    swarm.add_peer_address(peer_id, addr.clone());
+                    swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                    dial_discovered(&mut swarm, peer_id);
+                    downloader.probe_peer(peer_id, &mut swarm.behaviour_mut().file_share);
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
+                for (peer_id, addr) in peers.into_iter().filter(|(peer_id, _)| access.allows(peer_id)) {
+                    info!(peer = %peer_id, address = %addr, "Peer expired");
+                    publish(NodeEvent::PeerExpired {
+                        peer: peer_id,
+                        address: addr.clone(),
+                    });
+                    swarm.behaviour_mut().kad.remove_address(&peer_id, &addr);
+                }
+            }
+            // Ask newly connected peers whether they hold content we are downloading
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                static_peers.connected(&peer_id);
+                if num_established.get() == 1 {
+                    publish(NodeEvent::PeerConnected {
+                        peer: peer_id,
+                        address: endpoint.get_remote_address().clone(),
+                    });
+                }
+                // Only addresses we dialed are worth remembering; inbound ones use ephemeral ports
+                let address = endpoint.is_dialer().then(|| endpoint.get_remote_address());
+                record(store.peer_seen(&peer_id, address));
+                downloader.probe_peer(peer_id, &mut swarm.behaviour_mut().file_share);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                downloader.peer_disconnected(&peer_id);
+                publish(NodeEvent::PeerDisconnected { peer: peer_id });
+                if let Some(delay) = static_peers.disconnected(&peer_id) {
+                    warn!(?delay, "Lost connection to static peer; reconnecting");
+                }
+            }
+            // A connection limit was reached
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error: ListenError::Denied { cause },
+                ..
+            } => {
+                // The cause itself only says "connection denied"; its source says why
+                let reason = cause.source().map_or_else(|| cause.to_string(), ToString::to_string);
+                info!(address = %send_back_addr, %reason, "Refused connection");
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } => {
+                if let Some(delay) = static_peers.dial_failed(&peer_id) {
+                    warn!(%error, ?delay, "Cannot connect to static peer; retrying");
+                }
+            }
+            // Received an identify event; the peer's info and listen addresses are stored
+            SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => {
+                if let identify::Event::Received { peer_id, info } = &event {
+                    record(store.peer_identified(peer_id, info));
+                    publish(NodeEvent::PeerIdentified {
+                        peer: *peer_id,
+                        agent_version: info.agent_version.clone(),
+                        protocol_version: info.protocol_version.clone(),
+                    });
+                    network::add_identified_peer(swarm.behaviour_mut(), peer_id, info);
+                }
+                debug!(?event, "Identify event");
+            }
+            // Received a ping event; RTTs tell the downloader which peers are slow
+            SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => {
+                if let Ok(rtt) = event.result {
+                    downloader.record_rtt(event.peer, rtt);
+                    publish(NodeEvent::Ping {
+                        peer: event.peer,
+                        rtt_ms: rtt.as_millis() as u64,
+                    });
+                }
+                debug!(?event, "Ping event");
+            }
+            // A peer asked for our file list, a manifest or a chunk
+            SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request_id, request, channel },
+            })) => {
+                // Chunks are logged within the span of the upload they belong to
+                let _upload = match &request {
+                    FileRequest::Chunk { root, index } => {
+                        let span = debug_span!("upload", %root).entered();
+                        debug!(index, "Serving chunk");
+                        Some(span)
+                    }
+                    request => {
+                        info!(?request, "File request");
+                        None
+                    }
+                };
+                let requested = match &request {
+                    // Later pages belong to the same listing
+                    FileRequest::List { offset: 0 } => Some(("list", None)),
+                    FileRequest::List { .. } => None,
+                    FileRequest::Manifest { file } => Some(("manifest", Some(file.clone()))),
+                    FileRequest::Chunk { root, .. } => Some(("chunk", Some(root.to_string()))),
+                    FileRequest::SearchResults { .. } => None,
+                };
+                if let Some((request, file)) = requested {
+                    publish(NodeEvent::FileRequested { peer, request, file });
+                }
+                let response = match request {
+                    // Answers to a search made through the control socket
+                    FileRequest::SearchResults { query_id, results } if pending.searches.contains_key(&query_id) => {
+                        pending.add_search_results(query_id, peer, results);
+                        FileResponse::Ack
+                    }
+                    request => serve_request(&index, &access, &peer, request),
+                };
+                if let FileResponse::Chunk { root, data, .. } = &response {
+                    metrics.chunk_served(data.len());
+                    publish(NodeEvent::ChunkServed {
+                        peer,
+                        root: *root,
+                        bytes: data.len() as u64,
+                    });
+                }
+                let is_chunk = matches!(response, FileResponse::Chunk { .. });
+                if swarm.behaviour_mut().file_share.send_response(channel, response).is_err() {
+                    warn!("Could not answer: connection closed");
+                } else if is_chunk {
+                    uploads.insert(request_id);
+                }
+            }
+            // A chunk finished sending, or could not be sent
+            SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(
+                request_response::Event::ResponseSent { request_id, .. }
+                | request_response::Event::InboundFailure { request_id, .. },
+            )) => {
+                uploads.remove(&request_id);
+            }
+            // A search query published on the search topic; matches are sent back to its author
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                let Some(source) = message.source else { continue };
+                let query: SearchQuery = match serde_json::from_slice(&message.data) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        warn!(error = %e, "Ignoring malformed search query");
+                        continue;
+                    }
+                };
+                let results: Vec<_> = index
+                    .files()
+                    .filter(|file| access.permits(index.rule_for(file), &source))
+                    .map(IndexedFile::entry)
+                    .filter(|entry| query.matches(entry))
+                    .take(MAX_SEARCH_RESULTS)
+                    .collect();
+                if results.is_empty() {
+                    debug!(?query, "Search without matches");
+                } else {
+                    info!(?query, matches = results.len(), "Search");
+                }
+                publish(NodeEvent::SearchReceived {
+                    peer: source,
+                    matches: results.len(),
+                });
+                if !results.is_empty() {
+                    swarm.behaviour_mut().file_share.send_request(
+                        &source,
+                        FileRequest::SearchResults {
+                            query_id: query.id,
+                            results,
+                        },
+                    );
+                }
+            }
+            // The first DHT peer makes it possible to publish the provider records
+            // announced while the routing table was still empty
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::RoutingUpdated {
+                peer,
+                is_new_peer: true,
+                ..
+            })) => {
+                let kad = &mut swarm.behaviour_mut().kad;
+                let routing_table_size: usize = kad.kbuckets().map(|bucket| bucket.num_entries()).sum();
+                debug!(%peer, routing_table_size, "Added peer to the DHT routing table");
+                if routing_table_size == 1 {
+                    for file in index.files() {
+                        announce(kad, &file.manifest.root);
+                    }
+                    find_providers(&mut swarm, &downloader);
+                }
+            }
+            // Peers announcing content we are downloading become download sources
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed {
+                result: kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })),
+                ..
+            })) => {
+                for provider in providers {
+                    if provider != local_peer_id {
+                        downloader.probe_peer(provider, &mut swarm.behaviour_mut().file_share);
+                    }
+                }
+            }
+            // A peer answered one of the downloader's requests, or the manifest
+            // request of a download started through the control socket
+            SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::Message {
+                message: request_response::Message::Response { request_id, response },
+                ..
+            })) => {
+                if let Some(start) = pending.downloads.remove(&request_id) {
+                    let result = start_download(start.peer, start.output, response, &mut swarm, &mut downloader, data_dir);
+                    let _ = start.reply.send(result);
+                } else {
+                    downloader.on_response(request_id, response);
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            })) => {
+                if let Some(start) = pending.downloads.remove(&request_id) {
+                    let _ = start.reply.send(Err(RpcError::failed(format!("cannot reach {}: {}", peer, error))));
+                } else {
+                    downloader.on_failure(request_id);
+                }
+            }
+            // Ignore all other events
+            _ => {}
+        }
+    }
+
+    info!("Shutting down");
+    let _ = fs::remove_file(&control_socket);
+    if cli.upnp {
+        release_port_mappings(&mut swarm, &listeners).await;
+    }
+    Ok(())
+}
+
+/// Completes when the process receives Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Closes the listeners, which makes the UPnP behaviour remove their port
+/// mappings, and keeps the swarm running long enough for the router to be told.
+async fn release_port_mappings(swarm: &mut Swarm<MyBehaviour>, listeners: &[ListenerId]) {
+    for listener in listeners {
+        swarm.remove_listener(*listener);
+    }
+    let deadline = tokio::time::sleep(UPNP_RELEASE_TIMEOUT);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = swarm.select_next_some() => {}
+        }
+    }
+}
+
+/// Announces in the DHT that this node provides `root`.
+fn announce(kad: &mut kad::Behaviour<MemoryStore>, root: &ContentHash) {
+    if let Err(e) = kad.start_providing(network::provider_key(root)) {
+        warn!(%root, error = %e, "Cannot announce content in the DHT");
+    }
+}
+
+/// Prints the reachability of the node as detected by AutoNAT.
+fn report_nat_status(status: &autonat::NatStatus, has_relay: bool) {
+    match status {
+        autonat::NatStatus::Public(address) => {
+            info!(%address, "NAT status: public");
+        }
+        autonat::NatStatus::Private if has_relay => {
+            info!("NAT status: private; peers on other networks connect through the relay");
+        }
+        autonat::NatStatus::Private => {
+            warn!("NAT status: private; peers on other networks cannot connect, use --relay to reserve a slot on a relay");
+        }
+        autonat::NatStatus::Unknown => info!("NAT status: unknown"),
+    }
+}
+
+/// Listens through `relay`, which makes the relay client reserve a slot on it.
+fn reserve_relay_slot(swarm: &mut Swarm<MyBehaviour>, relay: &Multiaddr) -> Result<ListenerId, Box<dyn Error>> {
+    network::peer_id_of(relay)?;
+    Ok(swarm.listen_on(relay.clone().with(Protocol::P2pCircuit))?)
+}
+
+/// Connects to a peer discovered through mDNS unless it is already connected
+/// or being dialed; mDNS supplies the addresses.
+fn dial_discovered(swarm: &mut Swarm<MyBehaviour>, peer_id: PeerId) {
+    let opts = DialOpts::peer_id(peer_id)
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .build();
+    match swarm.dial(opts) {
+        Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+        Err(error) => warn!(peer = %peer_id, %error, "Cannot dial"),
+    }
+}
+
+/// Whether a listen address can be handed out to peers on other hosts.
+fn is_shareable_address(address: &Multiaddr) -> bool {
+    address.iter().all(|protocol| match protocol {
+        Protocol::Ip4(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+        Protocol::Ip6(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+        Protocol::P2pCircuit => false,
+        _ => true,
+    })
+}
+
+/// Starts a DHT lookup for the providers of every unfinished download.
+fn find_providers(swarm: &mut Swarm<MyBehaviour>, downloader: &Downloader) {
+    for root in downloader.roots() {
+        swarm.behaviour_mut().kad.get_providers(network::provider_key(root));
+    }
+}
+
+/// Reports a failed store update; the node keeps running without it.
+fn record(result: Result<(), Box<dyn Error>>) {
+    if let Err(e) = result {
+        warn!(error = %e, "Cannot update the store");
+    }
+}
+
+/// Logs the progress of a download within its span.
+fn report_download_event(event: DownloadEvent) {
+    let root = match &event {
+        DownloadEvent::Progress { root, .. }
+        | DownloadEvent::ChunkFailed { root, .. }
+        | DownloadEvent::Completed { root, .. }
+        | DownloadEvent::PeerRejected { root, .. }
+        | DownloadEvent::Failed { root, .. } => *root,
+    };
+    let _span = info_span!("download", %root).entered();
+    match event {
+        DownloadEvent::Progress { completed, total, .. } => debug!(completed, total, "Chunk verified"),
+        DownloadEvent::ChunkFailed { peer, error, .. } => debug!(%peer, %error, "Chunk failed"),
+        DownloadEvent::Completed { path, .. } => info!(path = %path.display(), "Download completed"),
+        DownloadEvent::PeerRejected { peer, error, .. } => warn!(%peer, %error, "No longer downloading from peer"),
+        DownloadEvent::Failed { error, .. } => error!(%error, "Download failed"),
+    }
+}
+
+/// The span of the connection or peer a swarm event concerns, if any.
+fn event_span(event: &SwarmEvent<MyBehaviourEvent>) -> Span {
+    match event {
+        SwarmEvent::ConnectionEstablished {
+            peer_id, connection_id, ..
+        }
+        | SwarmEvent::ConnectionClosed {
+            peer_id, connection_id, ..
+        }
+        | SwarmEvent::OutgoingConnectionError {
+            peer_id: Some(peer_id),
+            connection_id,
+            ..
+        } => info_span!("connection", peer = %peer_id, id = ?connection_id),
+        SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => {
+            info_span!("connection", peer = %event.peer, id = ?event.connection)
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, .. }))
+        | SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::Message { peer: peer_id, .. })) => {
+            info_span!("peer", peer = %peer_id)
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+            match message.source {
+                Some(source) => info_span!("peer", peer = %source),
+                None => Span::none(),
+            }
+        }
+        _ => Span::none(),
+    }
+}
+
+/// Builds the response to a file request from `peer` from the share index,
+/// leaving out the files it may not access.
+fn serve_request(index: &ShareIndex, access: &PeerAccess, peer: &PeerId, request: FileRequest) -> FileResponse {
+    let permitted = |file: &IndexedFile| access.permits(index.rule_for(file), peer);
+    match request {
+        FileRequest::List { offset } => {
+            // Sorted, so that consecutive pages line up
+            let mut entries: Vec<FileEntry> = index.files().filter(|file| permitted(file)).map(IndexedFile::entry).collect();
+            entries.sort_by(|a, b| (&a.name, a.root).cmp(&(&b.name, b.root)));
+            let mut rest = entries.into_iter().skip(offset as usize);
+            let entries = rest.by_ref().take(LIST_PAGE_SIZE).collect();
+            FileResponse::List { entries, more: rest.next().is_some() }
+        }
+        FileRequest::Manifest { file } => {
+            let found: Vec<_> = index.find_all(&file).collect();
+            match found.iter().find(|file| permitted(file)) {
+                Some(indexed) => FileResponse::Manifest(indexed.manifest.clone()),
+                None if found.is_empty() => FileResponse::Error(format!("no shared file named {}", file)),
+                None => FileResponse::Denied(file),
+            }
+        }
+        FileRequest::Chunk { root, index: chunk } => {
+            let found: Vec<_> = index.with_root(&root).collect();
+            match found.iter().find(|file| permitted(file)) {
+                Some(indexed) => match indexed.manifest.read_chunk(&indexed.path, chunk) {
+                    Ok(data) => FileResponse::Chunk {
+                        root,
+                        index: chunk,
+                        data,
+                    },
+                    Err(e) => FileResponse::Error(format!("cannot serve chunk {} of {}: {}", chunk, root, e)),
+                },
+                None if found.is_empty() => FileResponse::Error(format!("no shared file with root {}", root)),
+                None => FileResponse::Denied(root.to_string()),
+            }
+        }
+        // The node does not search by itself, so there is nothing to do with results
+        FileRequest::SearchResults { .. } => FileResponse::Ack,
+    }
+}
+
+/// Control socket calls answered once the network has responded
+#[derive(Default)]
+struct PendingCalls {
+    /// Searches collecting answers until their deadline, by query id
+    searches: HashMap<u64, PendingSearch>,
+    /// Downloads waiting for their manifest, by the id of the manifest request
+    downloads: HashMap<OutboundRequestId, PendingDownload>,
+}
+
+/// A `search` call collecting answers
+struct PendingSearch {
+    deadline: Instant,
+    /// Answers by peer, root hash and name, so duplicates are merged
+    results: BTreeMap<(PeerId, ContentHash, String), FileEntry>,
+    reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+/// A `download_start` call waiting for the manifest
+struct PendingDownload {
+    peer: PeerId,
+    output: Option<PathBuf>,
+    reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+impl PendingCalls {
+    /// When the next search is due to be answered, if any.
+    fn next_search_deadline(&self) -> Option<Instant> {
+        self.searches.values().map(|search| search.deadline).min()
+    }
+
+    fn add_search_results(&mut self, query_id: u64, peer: PeerId, entries: Vec<FileEntry>) {
+        if let Some(search) = self.searches.get_mut(&query_id) {
+            for entry in entries {
+                search.results.insert((peer, entry.root, entry.name.clone()), entry);
+            }
+        }
+    }
+
+    /// Answers every search whose deadline has passed with the results collected.
+    fn finish_searches(&mut self) {
+        let now = Instant::now();
+        let due: Vec<u64> = self
+            .searches
+            .iter()
+            .filter(|(_, search)| search.deadline <= now)
+            .map(|(query_id, _)| *query_id)
+            .collect();
+        for query_id in due {
+            let Some(search) = self.searches.remove(&query_id) else { continue };
+            let results: Vec<Value> = search
+                .results
+                .into_iter()
+                .map(|((peer, _, _), entry)| {
+                    let mut result = json!(entry);
+                    result["peer"] = json!(peer.to_string());
+                    result
+                })
+                .collect();
+            let _ = search.reply.send(Ok(Value::Array(results)));
+        }
+    }
+}
+
+/// Carries out a control socket call. Calls that wait for the network are
+/// recorded in `pending` and answered later.
+fn handle_call(
+    call: RpcCall,
+    swarm: &mut Swarm<MyBehaviour>,
+    index: &ShareIndex,
+    downloader: &mut Downloader,
+    store: &Store,
+    data_dir: &Path,
+    pending: &mut PendingCalls,
+) {
+    let RpcCall { method, params, reply } = call;
+    let result = match method.as_str() {
+        "share_add" => share_add(data_dir, params),
+        "files_list" => Ok(files_list(index)),
+        "search" => match begin_search(swarm, params) {
+            Ok((query_id, deadline)) => {
+                let search = PendingSearch {
+                    deadline,
+                    results: BTreeMap::new(),
+                    reply,
+                };
+                pending.searches.insert(query_id, search);
+                return;
+            }
+            Err(e) => Err(e),
+        },
+        "download_start" => match rpc::parse_params::<rpc::DownloadStartParams>(params) {
+            Ok(params) => {
+                let request = FileRequest::Manifest { file: params.file };
+                let request_id = swarm.behaviour_mut().file_share.send_request(&params.peer, request);
+                let download = PendingDownload {
+                    peer: params.peer,
+                    output: params.output,
+                    reply,
+                };
+                pending.downloads.insert(request_id, download);
+                return;
+            }
+            Err(e) => Err(e),
+        },
+        "download_status" => download_status(store, downloader, params),
+        "peers_list" => peers_list(swarm, store),
+        "status" => Ok(node_status(swarm)),
+        "dial" => rpc::parse_params::<rpc::DialParams>(params).and_then(|params| {
+            swarm.dial(params.address).map_err(RpcError::failed)?;
+            Ok(Value::Null)
+        }),
+        _ => Err(RpcError::method_not_found(&method)),
+    };
+    let _ = reply.send(result);
+}
+
+/// `share_add`: adds paths to the share list; the indexer picks them up.
+fn share_add(data_dir: &Path, params: Value) -> Result<Value, RpcError> {
+    let params: rpc::ShareAddParams = rpc::parse_params(params)?;
+    let mut shares = Shares::load(data_dir).map_err(RpcError::failed)?;
+    let mut added = Vec::new();
+    for path in &params.paths {
+        let is_new = shares.add(path).map_err(RpcError::failed)?;
+        added.push(json!({ "path": path, "added": is_new }));
+    }
+    shares.save(data_dir).map_err(RpcError::failed)?;
+    Ok(Value::Array(added))
+}
+
+/// `files_list`: the indexed files with their location and access rule.
+fn files_list(index: &ShareIndex) -> Value {
+    index
+        .files()
+        .map(|file| {
+            let mut entry = json!(file.entry());
+            entry["path"] = json!(file.path);
+            entry["access"] = json!(index.rule_for(file).to_string());
+            entry
+        })
+        .collect()
+}
+
+/// `search`: publishes a query to the connected peers, returning its id and
+/// when to answer the call with the results.
+fn begin_search(swarm: &mut Swarm<MyBehaviour>, params: Value) -> Result<(u64, Instant), RpcError> {
+    let params: rpc::SearchParams = rpc::parse_params(params)?;
+    let query = SearchQuery {
+        id: rand::random(),
+        name: params.name,
+        extension: params.extension,
+        min_size: params.min_size,
+        max_size: params.max_size,
+        root: params.root,
+    };
+    let data = serde_json::to_vec(&query).map_err(RpcError::failed)?;
+    match swarm.behaviour_mut().gossipsub.publish(search::search_topic(), data) {
+        Ok(_) => Ok((query.id, Instant::now() + Duration::from_secs(params.timeout))),
+        Err(gossipsub::PublishError::InsufficientPeers) => Err(RpcError::failed("no connected peer to search")),
+        Err(e) => Err(RpcError::failed(e)),
+    }
+}
+
+/// `download_start`, once `peer` answered the manifest request: starts the
+/// download and looks for more peers holding the content.
+fn start_download(
+    peer: PeerId,
+    output: Option<PathBuf>,
+    response: FileResponse,
+    swarm: &mut Swarm<MyBehaviour>,
+    downloader: &mut Downloader,
+    data_dir: &Path,
+) -> Result<Value, RpcError> {
+    let manifest = match response {
+        FileResponse::Manifest(manifest) => manifest,
+        FileResponse::Denied(file) => return Err(RpcError::failed(format!("access to {} denied", file))),
+        FileResponse::Error(message) => return Err(RpcError::failed(message)),
+        other => return Err(RpcError::failed(format!("unexpected response: {:?}", other))),
+    };
+    let root = manifest.root;
+    if downloader.roots().any(|active| *active == root) {
+        return Err(RpcError::failed(format!("already downloading {}", root)));
+    }
+    // Two downloads writing to one path would mix their chunks
+    let output = match output {
+        Some(output) => {
+            let output = std::path::absolute(output).map_err(RpcError::failed)?;
+            if output_taken(downloader, &output) {
+                return Err(RpcError::failed(format!("{} already exists or is being downloaded", output.display())));
+            }
+            output
+        }
+        None => {
+            let name = Path::new(&manifest.name)
+                .file_name()
+                .ok_or_else(|| RpcError::failed(format!("cannot derive a file name from {}", manifest.name)))?;
+            let output = std::path::absolute(data_dir.join("downloads").join(name)).map_err(RpcError::failed)?;
+            free_output(downloader, output)
+        }
+    };
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(RpcError::failed)?;
+    }
+
+    let download = Download::open(manifest, &output).map_err(RpcError::failed)?;
+    downloader.start(download).map_err(RpcError::failed)?;
+    downloader.add_provider(root, peer);
+    info!(%root, output = %output.display(), "Downloading");
+    let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for other in connected {
+        downloader.probe_peer(other, &mut swarm.behaviour_mut().file_share);
+    }
+    swarm.behaviour_mut().kad.get_providers(network::provider_key(&root));
+    Ok(json!({ "root": root, "output": output }))
+}
+
+/// Whether `output` exists, or is the output of a download, in progress or left behind.
+fn output_taken(downloader: &Downloader, output: &Path) -> bool {
+    output.exists() || download::part_path(output).exists() || downloader.outputs().any(|active| active == output)
+}
+
+/// `output` if it is free, otherwise the first free one of `name (1).ext`,
+/// `name (2).ext` and so on next to it.
+fn free_output(downloader: &Downloader, output: PathBuf) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = output.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+    let mut candidate = output.clone();
+    for n in 1.. {
+        if !output_taken(downloader, &candidate) {
+            break;
+        }
+        candidate = output.with_file_name(format!("{} ({}){}", stem, n, extension.as_deref().unwrap_or("")));
+    }
+    candidate
+}
+
+/// `download_status`: the download history, with the progress of those in progress.
+fn download_status(store: &Store, downloader: &Downloader, params: Value) -> Result<Value, RpcError> {
+    let params: rpc::DownloadStatusParams = rpc::parse_params(params)?;
+    let records = store.downloads().map_err(RpcError::failed)?;
+    Ok(records
+        .into_iter()
+        .filter(|record| params.root.is_none_or(|root| record.root == root))
+        .map(|record| {
+            let mut status = json!({
+                "root": record.root,
+                "name": record.name,
+                "size": record.size,
+                "output": record.output,
+                "status": record.status.as_str(),
+                "error": record.error,
+                "started_at": record.started_at,
+                "finished_at": record.finished_at,
+            });
+            if let Some((completed, total)) = downloader.progress(&record.root) {
+                status["completed_chunks"] = json!(completed);
+                status["total_chunks"] = json!(total);
+                status["chunk_size"] = json!(downloader.chunk_size(&record.root));
+                status["providers"] = json!(downloader.provider_count(&record.root));
+            }
+            status
+        })
+        .collect())
+}
+
+/// `status`: the node's identity, addresses and reachability.
+fn node_status(swarm: &Swarm<MyBehaviour>) -> Value {
+    let autonat = &swarm.behaviour().autonat;
+    json!({
+        "peer_id": swarm.local_peer_id().to_string(),
+        "nat_status": events::nat_status_name(&autonat.nat_status()),
+        "public_address": autonat.public_address().map(ToString::to_string),
+        "listen_addresses": swarm.listeners().map(ToString::to_string).collect::<Vec<_>>(),
+        "connected_peers": swarm.connected_peers().count(),
+    })
+}
+
+/// `peers_list`: the connected peers, with what identify told about them.
+fn peers_list(swarm: &Swarm<MyBehaviour>, store: &Store) -> Result<Value, RpcError> {
+    let known: HashMap<PeerId, _> = store
+        .known_peers()
+        .map_err(RpcError::failed)?
+        .into_iter()
+        .map(|peer| (peer.peer_id, peer))
+        .collect();
+    Ok(swarm
+        .connected_peers()
+        .map(|peer_id| {
+            let peer = known.get(peer_id);
+            json!({
+                "peer_id": peer_id.to_string(),
+                "agent_version": peer.and_then(|peer| peer.agent_version.clone()),
+                "protocol_version": peer.and_then(|peer| peer.protocol_version.clone()),
+                "addresses": peer.map(|peer| peer.addresses.iter().map(ToString::to_string).collect::<Vec<_>>()),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{AccessRule, AccessRules};
+    use crate::manifest::{Manifest, CHUNK_SIZE};
+    use clap::Parser;
+    use libp2p::identity::Keypair;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Where SSDP searches for gateways are sent
+    const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+    /// Public address the stand-in router reports; it must look routable
+    const EXTERNAL_IP: &str = "1.2.3.4";
+
+    const ROOT_DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <SCPDURL>/scpd.xml</SCPDURL>
+        <controlURL>/control</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#;
+
+    const SERVICE_DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+  <actionList>
+    <action>
+      <name>AddPortMapping</name>
+      <argumentList>
+        <argument><name>NewRemoteHost</name><direction>in</direction></argument>
+        <argument><name>NewExternalPort</name><direction>in</direction></argument>
+        <argument><name>NewProtocol</name><direction>in</direction></argument>
+        <argument><name>NewInternalPort</name><direction>in</direction></argument>
+        <argument><name>NewInternalClient</name><direction>in</direction></argument>
+        <argument><name>NewEnabled</name><direction>in</direction></argument>
+        <argument><name>NewPortMappingDescription</name><direction>in</direction></argument>
+        <argument><name>NewLeaseDuration</name><direction>in</direction></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>DeletePortMapping</name>
+      <argumentList>
+        <argument><name>NewRemoteHost</name><direction>in</direction></argument>
+        <argument><name>NewExternalPort</name><direction>in</direction></argument>
+        <argument><name>NewProtocol</name><direction>in</direction></argument>
+      </argumentList>
+    </action>
+  </actionList>
+</scpd>"#;
+
+    /// Port mappings as requested from the stand-in router: action, protocol and external port
+    type Requests = Arc<Mutex<Vec<(String, String, u16)>>>;
+
+    /// Starts a minimal Internet Gateway Device: an SSDP responder pointing at
+    /// an HTTP server on loopback that serves the descriptions and records the
+    /// SOAP calls it receives.
+    fn spawn_gateway() -> Requests {
+        let http = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let location = format!("http://{}/root.xml", http.local_addr().unwrap());
+        let requests = Requests::default();
+
+        let ssdp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 1900)).unwrap();
+        ssdp.join_multicast_v4(&SSDP_GROUP, &Ipv4Addr::UNSPECIFIED).unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            while let Ok((_, from)) = ssdp.recv_from(&mut buffer) {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                     ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                     USN: uuid:test::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                     LOCATION: {}\r\n\r\n",
+                    location
+                );
+                let _ = ssdp.send_to(response.as_bytes(), from);
+            }
+        });
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in http.incoming().flatten() {
+                serve_http(stream, &recorded);
+            }
+        });
+        requests
+    }
+
+    /// Answers one HTTP request of the gateway client.
+    fn serve_http(stream: TcpStream, requests: &Requests) {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        let mut action = String::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').unwrap_or((header, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap(),
+                "soapaction" => action = value.trim().trim_matches('"').rsplit('#').next().unwrap().to_string(),
+                _ => {}
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+
+        let response = if request_line.starts_with("GET /root.xml") {
+            ROOT_DESCRIPTION.to_string()
+        } else if request_line.starts_with("GET /scpd.xml") {
+            SERVICE_DESCRIPTION.to_string()
+        } else {
+            let result = match action.as_str() {
+                "GetExternalIPAddress" => format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", EXTERNAL_IP),
+                _ => String::new(),
+            };
+            if action != "GetExternalIPAddress" {
+                let protocol = xml_value(&body, "NewProtocol");
+                let port = xml_value(&body, "NewExternalPort").parse().unwrap();
+                requests.lock().unwrap().push((action.clone(), protocol, port));
+            }
+            format!(
+                r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:{action}Response xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">{result}</u:{action}Response></s:Body></s:Envelope>"#
+            )
+        };
+        let mut stream = reader.into_inner();
+        let _ = write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.len(),
+            response
+        );
+    }
+
+    /// Text of the first `<tag>` element in `xml`.
+    fn xml_value(xml: &str, tag: &str) -> String {
+        let start = xml.find(&format!("<{}>", tag)).unwrap() + tag.len() + 2;
+        let end = xml[start..].find('<').unwrap() + start;
+        xml[start..end].to_string()
+    }
+
+    // Not hermetic, so not run by default: the stand-in router takes UDP port
+    // 1900 on every interface, a real router on the LAN may answer the search
+    // first, and only private IPv4 addresses are mapped, so the host needs one.
+    // Run with `cargo test -- --ignored` on a machine without a UPnP daemon.
+    #[tokio::test]
+    #[ignore = "binds the SSDP port and multicasts on the LAN"]
+    async fn port_mappings_are_released_on_shutdown() {
+        let requests = spawn_gateway();
+        let data_dir = tempfile::tempdir().unwrap();
+        let cli = Cli::parse_from(["rust_p2p_share", "--upnp", "--listen", "/ip4/0.0.0.0/tcp/0"]);
+        let access = PeerAccess::new(&cli, data_dir.path()).unwrap();
+        let mut swarm = network::build_swarm(&Keypair::generate_ed25519(), &cli, &access).unwrap();
+        let listeners = network::listen(&mut swarm, &cli).unwrap();
+
+        let mapped = tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                match swarm.select_next_some().await {
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Upnp(upnp::Event::NewExternalAddr(address))) => {
+                        return address;
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Upnp(event)) => panic!("unexpected event: {:?}", event),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("the port was not mapped");
+        let port = match mapped.iter().nth(1) {
+            Some(Protocol::Tcp(port)) => port,
+            _ => panic!("unexpected external address {}", mapped),
+        };
+        assert!(mapped.to_string().starts_with(&format!("/ip4/{}/", EXTERNAL_IP)));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [("AddPortMapping".to_string(), "TCP".to_string(), port)]
+        );
+
+        release_port_mappings(&mut swarm, &listeners).await;
+        assert_eq!(
+            requests.lock().unwrap().last(),
+            Some(&("DeletePortMapping".to_string(), "TCP".to_string(), port))
+        );
+    }
+
+    /// An index of `count` shared files named `file-<n>`, the first of them
+    /// restricted to peers other than the one listing them.
+    fn index_of(count: usize) -> ShareIndex {
+        let mut index = ShareIndex::new();
+        for n in 0..count {
+            let name = format!("file-{:05}", n);
+            index.apply(IndexUpdate::Indexed(IndexedFile {
+                path: PathBuf::from("/shares").join(&name),
+                modified: std::time::UNIX_EPOCH,
+                mime: "application/octet-stream".to_string(),
+                manifest: Manifest {
+                    root: ContentHash::of(name.as_bytes()),
+                    name,
+                    size: 0,
+                    chunk_size: CHUNK_SIZE,
+                    chunks: Vec::new(),
+                },
+            }));
+        }
+        let mut rules = AccessRules::default();
+        let restricted = AccessRule::Restricted {
+            peers: [PeerId::random()].into(),
+            groups: Default::default(),
+        };
+        rules.set("/shares/file-00000".into(), restricted);
+        index.apply(IndexUpdate::Rules(rules));
+        index
+    }
+
+    #[test]
+    fn lists_bigger_than_a_page_are_served_in_pages() {
+        let index = index_of(LIST_PAGE_SIZE + 5);
+        let access = PeerAccess {
+            private: false,
+            trusted: Default::default(),
+            blocked: Default::default(),
+            groups: Default::default(),
+        };
+        let peer = PeerId::random();
+
+        let mut names = Vec::new();
+        let mut pages = 0;
+        loop {
+            let offset = names.len() as u32;
+            let FileResponse::List { entries, more } = serve_request(&index, &access, &peer, FileRequest::List { offset }) else {
+                panic!("not a list");
+            };
+            pages += 1;
+            assert!(entries.len() <= LIST_PAGE_SIZE);
+            names.extend(entries.into_iter().map(|entry| entry.name));
+            if !more {
+                break;
+            }
+        }
+        assert_eq!(pages, 2);
+        let expected: Vec<_> = (1..LIST_PAGE_SIZE + 5).map(|n| format!("file-{:05}", n)).collect();
+        assert_eq!(names, expected);
+    }
+
+    /// The answer of a peer sharing `contents` under `name`.
+    fn manifest_response(dir: &Path, name: &str, contents: &[u8]) -> FileResponse {
+        let path = dir.join(ContentHash::of(contents).to_string());
+        fs::write(&path, contents).unwrap();
+        FileResponse::Manifest(Manifest::from_file(&path, name.to_string()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn downloads_never_share_an_output_path() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let sources = tempfile::tempdir().unwrap();
+        let cli = Cli::parse_from(["rust_p2p_share"]);
+        let access = PeerAccess::new(&cli, data_dir.path()).unwrap();
+        let mut swarm = network::build_swarm(&Keypair::generate_ed25519(), &cli, &access).unwrap();
+        let mut downloader = Downloader::new(Rc::new(Store::open(data_dir.path()).unwrap()));
+        let mut start = |output: Option<PathBuf>, contents: &[u8]| {
+            let response = manifest_response(sources.path(), "music/song.mp3", contents);
+            start_download(PeerId::random(), output, response, &mut swarm, &mut downloader, data_dir.path())
+                .map(|started| PathBuf::from(started["output"].as_str().unwrap()))
+        };
+
+        // Different files with the same name are numbered
+        let downloads = std::path::absolute(data_dir.path().join("downloads")).unwrap();
+        assert_eq!(start(None, b"first").unwrap(), downloads.join("song.mp3"));
+        assert_eq!(start(None, b"second").unwrap(), downloads.join("song (1).mp3"));
+        // So are names of files that exist already
+        fs::write(downloads.join("song (2).mp3"), "mine").unwrap();
+        assert_eq!(start(None, b"third").unwrap(), downloads.join("song (3).mp3"));
+
+        // An explicit output must be free
+        assert!(start(Some(downloads.join("song.mp3")), b"fourth").is_err());
+        assert!(start(Some(downloads.join("song (2).mp3")), b"fourth").is_err());
+        assert_eq!(fs::read(downloads.join("song (2).mp3")).unwrap(), b"mine");
+        assert_eq!(start(Some(downloads.join("other.mp3")), b"fourth").unwrap(), downloads.join("other.mp3"));
+    }
+}
@@ -0,0 +1,70 @@
+//! File-sharing request/response protocol.
+//!
+//! Messages are serde types exchanged as CBOR over the versioned
+//! `/rust-p2p-share/file/3.0.0` protocol. Files are transferred chunk by
+//! chunk: a peer first fetches the [`Manifest`] of a file and then requests
+//! each chunk individually by root hash and index.
+
+use crate::manifest::{ContentHash, Manifest};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+
+/// Protocol name negotiated on file-sharing streams
+pub const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/rust-p2p-share/file/3.0.0");
+
+/// Most entries in one page of a file list; even with names of a few KiB a
+/// page stays well below the 10 MiB limit on responses
+pub const LIST_PAGE_SIZE: usize = 1000;
+
+/// Requests a peer can send to another peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileRequest {
+    /// Ask for the page of the shared files starting at entry `offset`
+    List { offset: u32 },
+    /// Ask for the manifest of a shared file, by name or root hash
+    Manifest { file: String },
+    /// Ask for one chunk of the file identified by `root`
+    Chunk { root: ContentHash, index: u32 },
+    /// Deliver the entries matching a search query the receiver published
+    SearchResults { query_id: u64, results: Vec<FileEntry> },
+}
+
+/// Responses to a [`FileRequest`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileResponse {
+    /// A page of the shared files of the responding peer, sorted by name;
+    /// `more` is set when further pages follow
+    List { entries: Vec<FileEntry>, more: bool },
+    /// Manifest of the requested file
+    Manifest(Manifest),
+    /// Contents of the requested chunk
+    Chunk {
+        root: ContentHash,
+        index: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// The request was accepted and needs no data in return
+    Ack,
+    /// The requesting peer may not access the named file or root hash
+    Denied(String),
+    /// The request could not be served
+    Error(String),
+}
+
+/// A shared file as seen by other peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Name relative to the shared directory, e.g. `photos/cat.jpg`
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Root hash of the file's manifest
+    pub root: ContentHash,
+    /// Modification time in seconds since the Unix epoch
+    #[serde(default)]
+    pub modified: u64,
+    /// MIME type guessed from the file extension
+    #[serde(default)]
+    pub mime: String,
+}
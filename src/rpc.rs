@@ -0,0 +1,389 @@
+//! Local control API: JSON-RPC 2.0 over a Unix domain socket.
+//!
+//! Every line received on the socket holds a request, or a batch of requests,
+//! and is answered with one line. Requests are handed to the node's event loop
+//! as [`RpcCall`]s, which replies once the call completes; a `search`, for
+//! instance, is answered when its timeout expires. Requests on one connection
+//! are handled in order, so tools wanting several at once open several
+//! connections.
+
+use crate::manifest::ContentHash;
+use libp2p::{Multiaddr, PeerId};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// File name of the control socket inside the data directory
+pub const CONTROL_SOCKET: &str = "control.sock";
+
+/// A request handed to the event loop, which answers it through `reply`
+#[derive(Debug)]
+pub struct RpcCall {
+    pub method: String,
+    /// Parameters as sent, `null` if there were none
+    pub params: Value,
+    pub reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+/// Error object of a JSON-RPC response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    /// The line is not valid JSON
+    pub const PARSE_ERROR: i64 = -32700;
+    /// The JSON is not a valid request object
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// The node stopped before answering
+    pub const INTERNAL_ERROR: i64 = -32603;
+    /// The call was valid but failed
+    pub const FAILED: i64 = -32000;
+
+    fn new(code: i64, message: impl fmt::Display) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(Self::METHOD_NOT_FOUND, format!("unknown method {}", method))
+    }
+
+    pub fn invalid_params(error: impl fmt::Display) -> Self {
+        Self::new(Self::INVALID_PARAMS, error)
+    }
+
+    pub fn failed(error: impl fmt::Display) -> Self {
+        Self::new(Self::FAILED, error)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl Error for RpcError {}
+
+/// Parses the parameters of a call; missing parameters parse like an empty object.
+pub fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(RpcError::invalid_params)
+}
+
+/// Parameters of `share_add`
+#[derive(Debug, Deserialize)]
+pub struct ShareAddParams {
+    pub paths: Vec<PathBuf>,
+}
+
+/// Parameters of `search`; every criterion is optional, as for the `search` command
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchParams {
+    pub name: Option<String>,
+    pub extension: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub root: Option<ContentHash>,
+    /// How long to collect answers, in seconds
+    #[serde(default = "default_search_timeout")]
+    pub timeout: u64,
+}
+
+fn default_search_timeout() -> u64 {
+    5
+}
+
+/// Parameters of `download_start`
+#[derive(Debug, Deserialize)]
+pub struct DownloadStartParams {
+    /// Peer to fetch the manifest from
+    pub peer: PeerId,
+    /// Name or root hash of the file
+    pub file: String,
+    /// Where to write the file, by default `downloads/<file name>` in the data
+    /// directory, numbered if that name is taken; an explicit path must be free
+    pub output: Option<PathBuf>,
+}
+
+/// Parameters of `download_status`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadStatusParams {
+    /// Only report the downloads of this content
+    pub root: Option<ContentHash>,
+}
+
+/// Parameters of `dial`
+#[derive(Debug, Deserialize)]
+pub struct DialParams {
+    pub address: Multiaddr,
+}
+
+/// Listens on the control socket at `path` and forwards every call to the
+/// returned receiver. A socket left behind by a node that is no longer
+/// running is replaced.
+#[cfg(unix)]
+pub fn spawn_server(path: &Path) -> Result<UnboundedReceiver<RpcCall>, Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.exists() {
+        if is_listening(path) {
+            return Err(format!("another node is listening on {}", path.display()).into());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?;
+    // Only the user running the node may control it
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    let (call_tx, call_rx) = unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_connection(stream, call_tx.clone()));
+                }
+                Err(e) => warn!(error = %e, "Cannot accept a control socket connection"),
+            }
+        }
+    });
+    Ok(call_rx)
+}
+
+/// Whether a node accepts calls on the control socket at `path`.
+#[cfg(unix)]
+pub fn is_listening(path: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+/// Unix domain sockets are not available, so no node is ever found.
+#[cfg(not(unix))]
+pub fn is_listening(_path: &Path) -> bool {
+    false
+}
+
+/// Unix domain sockets are not available, so no call ever arrives.
+#[cfg(not(unix))]
+pub fn spawn_server(_path: &Path) -> Result<UnboundedReceiver<RpcCall>, Box<dyn Error>> {
+    warn!("The control socket is only available on Unix");
+    Ok(unbounded_channel().1)
+}
+
+/// Answers the requests of one client until it disconnects.
+#[cfg(unix)]
+async fn serve_connection(stream: tokio::net::UnixStream, calls: UnboundedSender<RpcCall>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(response) = handle_line(&line, &calls).await else {
+            continue;
+        };
+        let mut bytes = response.to_string().into_bytes();
+        bytes.push(b'\n');
+        if writer.write_all(&bytes).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Answers a line holding a request or a batch; returns `None` when there is
+/// nothing to send back because it only held notifications.
+async fn handle_line(line: &str, calls: &UnboundedSender<RpcCall>) -> Option<Value> {
+    let message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(RpcError::PARSE_ERROR, e))),
+    };
+    match message {
+        Value::Array(requests) if requests.is_empty() => Some(error_response(
+            Value::Null,
+            RpcError::new(RpcError::INVALID_REQUEST, "empty batch"),
+        )),
+        Value::Array(requests) => {
+            let mut responses = Vec::new();
+            for request in requests {
+                responses.extend(handle_request(request, calls).await);
+            }
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        request => handle_request(request, calls).await,
+    }
+}
+
+/// Hands one request to the event loop and builds the response, or returns
+/// `None` for a notification, which has no `id` and gets no response.
+async fn handle_request(request: Value, calls: &UnboundedSender<RpcCall>) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let invalid = |message: &str| {
+        let id = id.clone().unwrap_or(Value::Null);
+        Some(error_response(id, RpcError::new(RpcError::INVALID_REQUEST, message)))
+    };
+    if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return invalid("jsonrpc must be \"2.0\"");
+    }
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return invalid("method must be a string");
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = forward(calls, method, params).await;
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => error_response(id, error),
+    })
+}
+
+/// Hands a call to the event loop and waits for its result.
+pub async fn forward(calls: &UnboundedSender<RpcCall>, method: &str, params: Value) -> Result<Value, RpcError> {
+    let stopped = || RpcError::new(RpcError::INTERNAL_ERROR, "the node stopped");
+    let (reply, result) = oneshot::channel();
+    let call = RpcCall {
+        method: method.to_string(),
+        params,
+        reply,
+    };
+    calls.send(call).map_err(|_| stopped())?;
+    result.await.unwrap_or_else(|_| Err(stopped()))
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+/// Sends one request to the node listening on `path` and returns its result.
+#[cfg(unix)]
+pub async fn call(path: &Path, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("cannot connect to a node on {}: {}", path.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+    let mut bytes = request.to_string().into_bytes();
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or("the node closed the connection")?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    if let Some(error) = response.get_mut("error") {
+        return Err(serde_json::from_value::<RpcError>(error.take())?.to_string().into());
+    }
+    Ok(response.get_mut("result").map(Value::take).unwrap_or(Value::Null))
+}
+
+/// Unix domain sockets are not available, so there is no node to talk to.
+#[cfg(not(unix))]
+pub async fn call(_path: &Path, _method: &str, _params: Value) -> Result<Value, Box<dyn Error>> {
+    Err("the control socket is only available on Unix".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts a stand-in for the node's event loop that knows `echo` and `dial`.
+    fn spawn_event_loop() -> UnboundedSender<RpcCall> {
+        let (calls, mut received) = unbounded_channel::<RpcCall>();
+        tokio::spawn(async move {
+            while let Some(call) = received.recv().await {
+                let result = match call.method.as_str() {
+                    "echo" => Ok(call.params),
+                    "dial" => parse_params::<DialParams>(call.params).map(|params| json!(params.address)),
+                    method => Err(RpcError::method_not_found(method)),
+                };
+                let _ = call.reply.send(result);
+            }
+        });
+        calls
+    }
+
+    fn error_code(response: &Value) -> Option<i64> {
+        response["error"]["code"].as_i64()
+    }
+
+    #[tokio::test]
+    async fn answers_calls_with_their_result() {
+        let calls = spawn_event_loop();
+        let response = handle_line(r#"{"jsonrpc":"2.0","method":"echo","params":[1],"id":7}"#, &calls).await;
+        assert_eq!(response, Some(json!({ "jsonrpc": "2.0", "result": [1], "id": 7 })));
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_a_parse_error() {
+        let calls = spawn_event_loop();
+        let response = handle_line(r#"{"jsonrpc":"2.0","method""#, &calls).await.unwrap();
+        assert_eq!(error_code(&response), Some(RpcError::PARSE_ERROR));
+        assert_eq!(response["id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn requests_must_be_json_rpc_2() {
+        let calls = spawn_event_loop();
+        let response = handle_line(r#"{"method":"echo","id":1}"#, &calls).await.unwrap();
+        assert_eq!(error_code(&response), Some(RpcError::INVALID_REQUEST));
+        let response = handle_line("[]", &calls).await.unwrap();
+        assert_eq!(error_code(&response), Some(RpcError::INVALID_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_are_reported() {
+        let calls = spawn_event_loop();
+        let response = handle_line(r#"{"jsonrpc":"2.0","method":"reboot","id":1}"#, &calls).await.unwrap();
+        assert_eq!(error_code(&response), Some(RpcError::METHOD_NOT_FOUND));
+        assert_eq!(response["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn bad_params_are_reported() {
+        let calls = spawn_event_loop();
+        for params in [r#"{}"#, r#"{"address":"not an address"}"#] {
+            let line = format!(r#"{{"jsonrpc":"2.0","method":"dial","params":{},"id":1}}"#, params);
+            let response = handle_line(&line, &calls).await.unwrap();
+            assert_eq!(error_code(&response), Some(RpcError::INVALID_PARAMS), "{}", params);
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let calls = spawn_event_loop();
+        assert_eq!(handle_line(r#"{"jsonrpc":"2.0","method":"echo"}"#, &calls).await, None);
+        let batch = r#"[{"jsonrpc":"2.0","method":"echo"},{"jsonrpc":"2.0","method":"echo","params":2,"id":"b"}]"#;
+        assert_eq!(
+            handle_line(batch, &calls).await,
+            Some(json!([{ "jsonrpc": "2.0", "result": 2, "id": "b" }]))
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_fail_once_the_node_stopped() {
+        let (calls, received) = unbounded_channel();
+        drop(received);
+        let response = handle_line(r#"{"jsonrpc":"2.0","method":"echo","id":1}"#, &calls).await.unwrap();
+        assert_eq!(error_code(&response), Some(RpcError::INTERNAL_ERROR));
+    }
+}
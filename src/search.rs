@@ -0,0 +1,243 @@
+//! Network-wide file search.
+//!
+//! A node looking for files publishes a [`SearchQuery`] on the gossipsub
+//! search topic. Every peer that has matching entries in its share index sends
+//! them straight back to the querier with a
+//! [`FileRequest::SearchResults`](crate::protocol::FileRequest::SearchResults)
+//! request on the file-sharing protocol.
+
+use crate::access::PeerAccess;
+use crate::cli::Cli;
+use crate::manifest::ContentHash;
+use crate::network::{self, MyBehaviourEvent};
+use crate::protocol::{FileEntry, FileRequest, FileResponse};
+use futures::StreamExt;
+use libp2p::{
+    gossipsub::{self, IdentTopic},
+    identity::Keypair,
+    mdns,
+    request_response,
+    swarm::{DialError, SwarmEvent},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+/// Name of the gossipsub topic carrying search queries
+pub const SEARCH_TOPIC: &str = "/rust-p2p-share/search/1.0.0";
+
+/// Largest number of entries a peer sends back for one query
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+/// The gossipsub topic carrying search queries.
+pub fn search_topic() -> IdentTopic {
+    IdentTopic::new(SEARCH_TOPIC)
+}
+
+/// Criteria for a search; every criterion that is set must match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchQuery {
+    /// Random identifier echoed in the results, so replies can be told apart
+    pub id: u64,
+    /// Case-insensitive substring of the shared name
+    pub name: Option<String>,
+    /// File extension without the leading dot, compared case-insensitively
+    pub extension: Option<String>,
+    /// Minimum size in bytes
+    pub min_size: Option<u64>,
+    /// Maximum size in bytes
+    pub max_size: Option<u64>,
+    /// Exact root hash
+    pub root: Option<ContentHash>,
+}
+
+impl SearchQuery {
+    /// Whether `entry` satisfies every criterion of the query.
+    pub fn matches(&self, entry: &FileEntry) -> bool {
+        if let Some(name) = &self.name {
+            if !entry.name.to_lowercase().contains(&name.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(extension) = &self.extension {
+            let actual = Path::new(&entry.name)
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+            if actual.as_deref() != Some(extension.trim_start_matches('.').to_lowercase().as_str()) {
+                return false;
+            }
+        }
+        if self.min_size.is_some_and(|min| entry.size < min) {
+            return false;
+        }
+        if self.max_size.is_some_and(|max| entry.size > max) {
+            return false;
+        }
+        if self.root.is_some_and(|root| entry.root != root) {
+            return false;
+        }
+        true
+    }
+}
+
+/// Publishes `query` to the peers found through mDNS and the bootstrap peers,
+/// and collects their answers for `timeout`.
+///
+/// The query is published again whenever another peer joins the search topic,
+/// so peers discovered late are asked too; duplicate answers are merged. The
+/// search fails only if the query could never be published.
+pub async fn search(
+    cli: &Cli,
+    local_key: &Keypair,
+    access: &PeerAccess,
+    mut query: SearchQuery,
+    timeout: Duration,
+) -> Result<Vec<(PeerId, FileEntry)>, Box<dyn Error>> {
+    let mut swarm = network::build_swarm(local_key, cli, access)?;
+    network::listen(&mut swarm, cli)?;
+    // Bootstrap peers relay the query to the rest of the network
+    network::bootstrap(&mut swarm, &cli.bootstrap_addrs)?;
+    query.id = rand::random();
+    let query_data = serde_json::to_vec(&query)?;
+
+    let mut results = BTreeMap::new();
+    let mut dialed = HashSet::new();
+    let mut published = false;
+    let mut publish_error = None;
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = swarm.select_next_some() => match event {
+                // Queries only reach connected peers, so connect to everyone allowed on the network
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                    for (peer_id, _) in found {
+                        if access.allows(&peer_id) && dialed.insert(peer_id) {
+                            match swarm.dial(peer_id) {
+                                // Already connected, e.g. as a bootstrap peer
+                                Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+                                Err(error) => warn!(peer = %peer_id, %error, "Cannot dial"),
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { topic, .. }))
+                    if topic == search_topic().hash() =>
+                {
+                    match swarm.behaviour_mut().gossipsub.publish(search_topic(), query_data.clone()) {
+                        Ok(_) => published = true,
+                        // The next peer joining the topic brings another chance
+                        Err(error) => {
+                            warn!(%error, "Cannot publish the search query");
+                            publish_error = Some(error);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::FileShare(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                })) => {
+                    let response = match request {
+                        FileRequest::SearchResults { query_id, results: entries } if query_id == query.id => {
+                            for entry in entries {
+                                results.insert((peer, entry.root, entry.name.clone()), entry);
+                            }
+                            FileResponse::Ack
+                        }
+                        FileRequest::SearchResults { .. } => FileResponse::Error("unknown search query".into()),
+                        _ => FileResponse::Error("this node does not share files".into()),
+                    };
+                    // The peer only learns whether we received its answer, so a failure can be ignored
+                    let _ = swarm.behaviour_mut().file_share.send_response(channel, response);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let (false, Some(error)) = (published, publish_error) {
+        return Err(format!("cannot publish the search query: {}", error).into());
+    }
+    Ok(results
+        .into_iter()
+        .map(|((peer, _, _), entry)| (peer, entry))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: u64) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            size,
+            root: ContentHash::of(name.as_bytes()),
+            modified: 0,
+            mime: String::new(),
+        }
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert!(SearchQuery::default().matches(&entry("photos/cat.jpg", 10)));
+    }
+
+    #[test]
+    fn name_is_a_case_insensitive_substring() {
+        let query = SearchQuery {
+            name: Some("CAT".to_string()),
+            ..Default::default()
+        };
+        assert!(query.matches(&entry("photos/Cat.jpg", 10)));
+        assert!(query.matches(&entry("concatenate.txt", 10)));
+        assert!(!query.matches(&entry("photos/dog.jpg", 10)));
+    }
+
+    #[test]
+    fn extension_ignores_case_and_leading_dot() {
+        let query = SearchQuery {
+            extension: Some(".JPG".to_string()),
+            ..Default::default()
+        };
+        assert!(query.matches(&entry("photos/cat.jpg", 10)));
+        assert!(query.matches(&entry("photos/cat.JPG", 10)));
+        assert!(!query.matches(&entry("photos/cat.jpeg", 10)));
+        assert!(!query.matches(&entry("photos/jpg", 10)));
+    }
+
+    #[test]
+    fn size_bounds_are_inclusive() {
+        let query = SearchQuery {
+            min_size: Some(10),
+            max_size: Some(20),
+            ..Default::default()
+        };
+        assert!(query.matches(&entry("a", 10)));
+        assert!(query.matches(&entry("a", 20)));
+        assert!(!query.matches(&entry("a", 9)));
+        assert!(!query.matches(&entry("a", 21)));
+    }
+
+    #[test]
+    fn every_criterion_must_match() {
+        let cat = entry("photos/cat.jpg", 10);
+        let query = SearchQuery {
+            name: Some("cat".to_string()),
+            root: Some(cat.root),
+            ..Default::default()
+        };
+        assert!(query.matches(&cat));
+
+        let query = SearchQuery {
+            root: Some(ContentHash::of(b"other")),
+            ..query
+        };
+        assert!(!query.matches(&cat));
+    }
+}
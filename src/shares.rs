@@ -0,0 +1,201 @@
+//! The list of paths this node shares and their access rules, persisted in the
+//! data directory.
+
+use crate::access::{AccessRule, AccessRules};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the share list inside the data directory
+const SHARES_FILE: &str = "shares.json";
+
+/// Paths the user has chosen to share with other peers
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Shares {
+    pub paths: Vec<PathBuf>,
+    /// Which peers may access the shared paths and what lies below them
+    #[serde(default)]
+    pub rules: AccessRules,
+}
+
+impl Shares {
+    /// Loads the share list from `data_dir`, returning an empty list if none was saved yet.
+    pub fn load(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = shares_path(data_dir);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let bytes = fs::read(&path)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Writes the share list to `data_dir`.
+    pub fn save(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(data_dir)?;
+        let json = serde_json::to_vec_pretty(self)?;
+        fs::write(shares_path(data_dir), json)?;
+        Ok(())
+    }
+
+    /// Adds a path, storing it in canonical form. Returns `false` if it was already shared.
+    pub fn add(&mut self, path: &Path) -> Result<bool, Box<dyn Error>> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| format!("cannot share {}: {}", path.display(), e))?;
+        if self.paths.contains(&path) {
+            return Ok(false);
+        }
+        self.paths.push(path);
+        Ok(true)
+    }
+
+    /// Sets the access rule of `path`, which must be shared or lie in a shared
+    /// folder, and returns it in canonical form.
+    pub fn set_rule(&mut self, path: &Path, rule: AccessRule) -> Result<PathBuf, Box<dyn Error>> {
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| format!("cannot restrict {}: {}", path.display(), e))?;
+        if self.root_of(&canonical).is_none() {
+            return Err(format!("{} is not shared", path.display()).into());
+        }
+        self.rules.set(canonical.clone(), rule);
+        Ok(canonical)
+    }
+
+    /// Returns every regular file covered by the shared paths, walking directories
+    /// recursively without following symbolic links.
+    pub fn files(&self) -> Vec<SharedFile> {
+        self.paths.iter().flat_map(|root| self.files_under(root)).collect()
+    }
+
+    /// Returns the shared files at or below `path`, which may be a file or a directory.
+    pub fn files_under(&self, path: &Path) -> Vec<SharedFile> {
+        let Some(root) = self.root_of(path) else {
+            return Vec::new();
+        };
+        // Names are relative to the parent of the shared path, so sharing
+        // `/home/me/photos` exposes `photos/cat.jpg`.
+        let base = root.parent().unwrap_or(root);
+        let mut paths = Vec::new();
+        collect_files(path, &mut paths);
+
+        let mut files = Vec::new();
+        for path in paths {
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            let name = path
+                .strip_prefix(base)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            files.push(SharedFile {
+                name,
+                path,
+                size: metadata.len(),
+            });
+        }
+        files
+    }
+
+    /// The most specific shared path containing `path`.
+    fn root_of(&self, path: &Path) -> Option<&Path> {
+        self.paths
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .map(PathBuf::as_path)
+    }
+}
+
+/// Location of the share list inside `data_dir`.
+pub fn shares_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SHARES_FILE)
+}
+
+/// A file covered by the share list
+#[derive(Debug, Clone)]
+pub struct SharedFile {
+    /// Name advertised to other peers
+    pub name: String,
+    /// Location on the local filesystem
+    pub path: PathBuf,
+    /// Size in bytes
+    pub size: u64,
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) {
+    // A link could point anywhere, e.g. at `~/.ssh`
+    let Ok(file_type) = fs::symlink_metadata(path).map(|metadata| metadata.file_type()) else {
+        return;
+    };
+    if file_type.is_file() {
+        if !is_download_artifact(path) {
+            files.push(path.to_path_buf());
+        }
+    } else if file_type.is_dir() {
+        let Ok(entries) = fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            collect_files(&entry.path(), files);
+        }
+    }
+}
+
+/// Whether `path` is a partial download or its state file, which must not be shared.
+fn is_download_artifact(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".part") || name.ends_with(".part.state") || name.ends_with(".state.tmp")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shared names of every shared file, sorted.
+    fn shared_names(shares: &Shares) -> Vec<String> {
+        let mut names: Vec<String> = shares.files().into_iter().map(|file| file.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn files_are_found_recursively_without_partial_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        fs::create_dir_all(share.join("sub")).unwrap();
+        fs::write(share.join("a.txt"), "a").unwrap();
+        fs::write(share.join("sub/b.txt"), "b").unwrap();
+        fs::write(share.join("sub/c.txt.part"), "c").unwrap();
+        fs::write(share.join("sub/c.txt.part.state"), "{}").unwrap();
+
+        let mut shares = Shares::default();
+        assert!(shares.add(&share).unwrap());
+        assert!(!shares.add(&share).unwrap());
+        assert_eq!(shared_names(&shares), ["share/a.txt", "share/sub/b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let secrets = dir.path().join("secrets");
+        fs::create_dir(&secrets).unwrap();
+        fs::write(secrets.join("id_ed25519"), "key").unwrap();
+        let share = dir.path().join("share");
+        fs::create_dir(&share).unwrap();
+        fs::write(share.join("a.txt"), "a").unwrap();
+        symlink(&secrets, share.join("linked-dir")).unwrap();
+        symlink(secrets.join("id_ed25519"), share.join("linked-file")).unwrap();
+
+        let mut shares = Shares::default();
+        shares.add(&share).unwrap();
+        assert_eq!(shared_names(&shares), ["share/a.txt"]);
+        assert!(shares.files_under(&share.join("linked-dir")).is_empty());
+        assert!(shares.files_under(&share.join("linked-file")).is_empty());
+    }
+}
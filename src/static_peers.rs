@@ -0,0 +1,192 @@
+//! Peers the node stays connected to.
+//!
+//! Static peers are given on the command line. The node dials them on startup
+//! and, whenever the connection is lost or a dial fails, dials them again after
+//! a delay that doubles with every consecutive failure. A connection only
+//! counts as a success, and resets the delay, once it has stayed up for a while;
+//! otherwise a peer that accepts connections and drops them at once would be
+//! redialed every second.
+
+use crate::network::{self, MyBehaviour};
+use libp2p::{
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        DialError, Swarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Delay before the first redial
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between two redials
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How long a connection must stay up before the backoff is reset
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+/// Connection state of one static peer
+struct StaticPeer {
+    addrs: Vec<Multiaddr>,
+    /// When the current connection was established, if connected
+    connected_since: Option<Instant>,
+    /// Delay applied after the next failure
+    backoff: Duration,
+    /// When to dial next, if a dial is due
+    next_dial: Option<Instant>,
+}
+
+/// The set of static peers and when each is to be dialed
+pub struct StaticPeers {
+    peers: HashMap<PeerId, StaticPeer>,
+}
+
+impl StaticPeers {
+    /// Creates the set from addresses ending in `/p2p/<peer id>`; every peer is due to be dialed.
+    pub fn new<'a>(addrs: impl IntoIterator<Item = &'a Multiaddr>) -> Result<Self, Box<dyn Error>> {
+        let mut peers: HashMap<PeerId, StaticPeer> = HashMap::new();
+        for addr in addrs {
+            let peer = peers.entry(network::peer_id_of(addr)?).or_insert_with(|| StaticPeer {
+                addrs: Vec::new(),
+                connected_since: None,
+                backoff: INITIAL_BACKOFF,
+                next_dial: Some(Instant::now()),
+            });
+            if !peer.addrs.contains(addr) {
+                peer.addrs.push(addr.clone());
+            }
+        }
+        Ok(StaticPeers { peers })
+    }
+
+    /// When the next dial is due, if any.
+    pub fn next_dial(&self) -> Option<Instant> {
+        self.peers.values().filter_map(|peer| peer.next_dial).min()
+    }
+
+    /// Dials every peer whose dial is due.
+    pub fn dial_due(&mut self, swarm: &mut Swarm<MyBehaviour>) {
+        let now = Instant::now();
+        for (peer_id, peer) in &mut self.peers {
+            if peer.next_dial.is_some_and(|at| at <= now) {
+                peer.next_dial = None;
+                let opts = DialOpts::peer_id(*peer_id)
+                    .addresses(peer.addrs.clone())
+                    .condition(PeerCondition::DisconnectedAndNotDialing)
+                    .build();
+                match swarm.dial(opts) {
+                    // Already connected or being dialed; the outcome is reported as usual
+                    Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+                    Err(error) => {
+                        let delay = peer.schedule(now);
+                        warn!(peer = %peer_id, %error, ?delay, "Cannot dial static peer; retrying");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records a connection to `peer_id`.
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.connected_since.get_or_insert_with(Instant::now);
+            peer.next_dial = None;
+        }
+    }
+
+    /// Records that the last connection to `peer_id` was closed and schedules
+    /// a redial, after the initial delay if the connection was stable. Returns
+    /// the delay, or `None` if the peer is not static.
+    pub fn disconnected(&mut self, peer_id: &PeerId) -> Option<Duration> {
+        let peer = self.peers.get_mut(peer_id)?;
+        let now = Instant::now();
+        if peer.connected_since.take().is_some_and(|since| now - since >= STABLE_CONNECTION) {
+            peer.backoff = INITIAL_BACKOFF;
+        }
+        Some(peer.schedule(now))
+    }
+
+    /// Records a failed dial of `peer_id` and schedules the next one with a
+    /// longer delay. Returns the delay, or `None` if the peer is not static or
+    /// is connected anyway.
+    pub fn dial_failed(&mut self, peer_id: &PeerId) -> Option<Duration> {
+        let peer = self.peers.get_mut(peer_id)?;
+        if peer.connected_since.is_some() {
+            return None;
+        }
+        Some(peer.schedule(Instant::now()))
+    }
+}
+
+impl StaticPeer {
+    /// Schedules the next dial after the current backoff and doubles it for the time after.
+    fn schedule(&mut self, now: Instant) -> Duration {
+        let delay = self.backoff;
+        self.next_dial = Some(now + delay);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn static_peer() -> (StaticPeers, PeerId) {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", peer_id).parse().unwrap();
+        (StaticPeers::new([&addr]).unwrap(), peer_id)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_doubles_with_every_failure_up_to_the_maximum() {
+        let (mut peers, peer_id) = static_peer();
+        assert_eq!(peers.next_dial(), Some(Instant::now()));
+
+        let delays: Vec<_> = (0..10).map(|_| peers.dial_failed(&peer_id).unwrap().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+        assert_eq!(peers.next_dial(), Some(Instant::now() + MAX_BACKOFF));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connected_peers_are_not_redialed() {
+        let (mut peers, peer_id) = static_peer();
+        peers.connected(&peer_id);
+        assert_eq!(peers.next_dial(), None);
+        assert_eq!(peers.dial_failed(&peer_id), None);
+        assert_eq!(peers.disconnected(&PeerId::random()), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn short_connections_keep_the_backoff() {
+        let (mut peers, peer_id) = static_peer();
+        peers.dial_failed(&peer_id);
+        peers.dial_failed(&peer_id);
+
+        peers.connected(&peer_id);
+        tokio::time::advance(STABLE_CONNECTION / 2).await;
+        assert_eq!(peers.disconnected(&peer_id), Some(Duration::from_secs(4)));
+        assert_eq!(peers.dial_failed(&peer_id), Some(Duration::from_secs(8)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stable_connections_reset_the_backoff() {
+        let (mut peers, peer_id) = static_peer();
+        peers.dial_failed(&peer_id);
+        peers.dial_failed(&peer_id);
+
+        peers.connected(&peer_id);
+        tokio::time::advance(STABLE_CONNECTION / 2).await;
+        // Further connections to the same peer do not restart the clock
+        peers.connected(&peer_id);
+        tokio::time::advance(STABLE_CONNECTION / 2).await;
+        assert_eq!(peers.disconnected(&peer_id), Some(INITIAL_BACKOFF));
+        assert_eq!(peers.next_dial(), Some(Instant::now() + INITIAL_BACKOFF));
+        assert_eq!(peers.dial_failed(&peer_id), Some(INITIAL_BACKOFF * 2));
+    }
+}
@@ -0,0 +1,483 @@
+//! Persistent metadata store.
+//!
+//! An SQLite database in the data directory holds the shared-file index, the
+//! peers this node has seen and the history of downloads. The schema version
+//! is kept in `PRAGMA user_version`; [`MIGRATIONS`] are applied in order on
+//! open, so upgrading the application never discards existing state.
+
+use crate::index::IndexedFile;
+use crate::manifest::{ContentHash, Manifest};
+use libp2p::{identify, Multiaddr, PeerId};
+use rusqlite::{params, Connection};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File name of the database inside the data directory
+const DATABASE_FILE: &str = "state.db";
+
+/// How long to wait for another process holding the database lock
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Schema migrations; the database is at version `n` once the first `n` have run.
+/// Existing entries must never change, new ones are appended.
+const MIGRATIONS: &[&str] = &[
+    // 1: downloads
+    "CREATE TABLE downloads (
+        output TEXT PRIMARY KEY,
+        root TEXT NOT NULL,
+        name TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        error TEXT,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER
+    );",
+    // 2: file index and peers
+    "CREATE TABLE files (
+        path TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        modified_ns INTEGER NOT NULL,
+        mime TEXT NOT NULL,
+        root TEXT NOT NULL,
+        manifest TEXT NOT NULL
+    );
+    CREATE INDEX files_root ON files (root);
+    CREATE TABLE peers (
+        peer_id TEXT PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        protocol_version TEXT,
+        agent_version TEXT,
+        protocols TEXT
+    );
+    CREATE TABLE peer_addresses (
+        peer_id TEXT NOT NULL REFERENCES peers (peer_id) ON DELETE CASCADE,
+        address TEXT NOT NULL,
+        last_seen INTEGER NOT NULL,
+        PRIMARY KEY (peer_id, address)
+    );",
+];
+
+/// Lifecycle of a download recorded in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadStatus {
+    /// Still being downloaded, or waiting to be resumed
+    Active,
+    /// All chunks verified and the file moved into place
+    Completed,
+    /// Given up, e.g. because its state file disappeared
+    Abandoned,
+}
+
+impl DownloadStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DownloadStatus::Active => "active",
+            DownloadStatus::Completed => "completed",
+            DownloadStatus::Abandoned => "abandoned",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "active" => DownloadStatus::Active,
+            "completed" => DownloadStatus::Completed,
+            _ => DownloadStatus::Abandoned,
+        }
+    }
+}
+
+/// A download as recorded in the history
+#[derive(Debug, Clone)]
+pub struct DownloadRecord {
+    pub output: PathBuf,
+    pub root: ContentHash,
+    pub name: String,
+    pub size: u64,
+    pub status: DownloadStatus,
+    pub error: Option<String>,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    /// Seconds since the Unix epoch
+    pub finished_at: Option<u64>,
+}
+
+/// A peer this node has seen
+#[derive(Debug, Clone)]
+pub struct KnownPeer {
+    pub peer_id: PeerId,
+    /// Seconds since the Unix epoch
+    pub last_seen: u64,
+    pub protocol_version: Option<String>,
+    pub agent_version: Option<String>,
+    pub protocols: Vec<String>,
+    /// Addresses the peer was seen at, most recent first
+    pub addresses: Vec<Multiaddr>,
+}
+
+/// Handle to the metadata database
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// Opens (creating if needed) the database in `data_dir` and brings its schema up to date.
+    pub fn open(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(data_dir)?;
+        let conn = Connection::open(data_dir.join(DATABASE_FILE))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let mut store = Store { conn };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// Applies every migration newer than the database's schema version.
+    fn migrate(&mut self) -> Result<(), Box<dyn Error>> {
+        let version: usize = self.conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(format!(
+                "database schema version {} is newer than this build supports ({})",
+                version,
+                MIGRATIONS.len()
+            )
+            .into());
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Stores or replaces an indexed file.
+    pub fn save_file(&self, file: &IndexedFile) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO files (path, name, size, modified_ns, mime, root, manifest)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                path_key(&file.path),
+                file.manifest.name,
+                file.manifest.size,
+                nanos_since_epoch(file.modified),
+                file.mime,
+                file.manifest.root.to_string(),
+                serde_json::to_string(&file.manifest)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes a file from the index.
+    pub fn remove_file(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.conn
+            .execute("DELETE FROM files WHERE path = ?1", params![path_key(path)])?;
+        Ok(())
+    }
+
+    /// Returns every indexed file.
+    pub fn files(&self) -> Result<Vec<IndexedFile>, Box<dyn Error>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, modified_ns, mime, manifest FROM files")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let (path, modified_ns, mime, manifest) = row?;
+            let manifest: Manifest = serde_json::from_str(&manifest)?;
+            files.push(IndexedFile {
+                path: PathBuf::from(path),
+                modified: UNIX_EPOCH + Duration::from_nanos(modified_ns as u64),
+                mime,
+                manifest,
+            });
+        }
+        Ok(files)
+    }
+
+    /// Records that `peer` was seen, optionally at `address`.
+    pub fn peer_seen(&self, peer: &PeerId, address: Option<&Multiaddr>) -> Result<(), Box<dyn Error>> {
+        let now = now();
+        self.conn.execute(
+            "INSERT INTO peers (peer_id, first_seen, last_seen) VALUES (?1, ?2, ?2)
+             ON CONFLICT (peer_id) DO UPDATE SET last_seen = excluded.last_seen",
+            params![peer.to_string(), now],
+        )?;
+        if let Some(address) = address {
+            self.conn.execute(
+                "INSERT INTO peer_addresses (peer_id, address, last_seen) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (peer_id, address) DO UPDATE SET last_seen = excluded.last_seen",
+                params![peer.to_string(), address.to_string(), now],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Records the information a peer sent through identify, including its listen addresses.
+    pub fn peer_identified(&self, peer: &PeerId, info: &identify::Info) -> Result<(), Box<dyn Error>> {
+        self.peer_seen(peer, None)?;
+        let protocols: Vec<String> = info.protocols.iter().map(ToString::to_string).collect();
+        self.conn.execute(
+            "UPDATE peers SET protocol_version = ?2, agent_version = ?3, protocols = ?4 WHERE peer_id = ?1",
+            params![
+                peer.to_string(),
+                info.protocol_version,
+                info.agent_version,
+                serde_json::to_string(&protocols)?,
+            ],
+        )?;
+        for address in &info.listen_addrs {
+            self.peer_seen(peer, Some(address))?;
+        }
+        Ok(())
+    }
+
+    /// Returns every known peer, most recently seen first.
+    pub fn known_peers(&self) -> Result<Vec<KnownPeer>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT peer_id, last_seen, protocol_version, agent_version, protocols
+             FROM peers ORDER BY last_seen DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+
+        let mut address_stmt = self.conn.prepare(
+            "SELECT address FROM peer_addresses WHERE peer_id = ?1 ORDER BY last_seen DESC",
+        )?;
+        let mut peers = Vec::new();
+        for row in rows {
+            let (peer_id, last_seen, protocol_version, agent_version, protocols) = row?;
+            let addresses = address_stmt
+                .query_map(params![peer_id], |row| row.get::<_, String>(0))?
+                .filter_map(|address| address.ok()?.parse().ok())
+                .collect();
+            peers.push(KnownPeer {
+                peer_id: peer_id.parse()?,
+                last_seen,
+                protocol_version,
+                agent_version,
+                protocols: protocols
+                    .and_then(|json| serde_json::from_str(&json).ok())
+                    .unwrap_or_default(),
+                addresses,
+            });
+        }
+        Ok(peers)
+    }
+
+    /// Records a newly started (or restarted) download as active.
+    pub fn download_started(&self, output: &Path, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT INTO downloads (output, root, name, size, status, started_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (output) DO UPDATE SET
+                root = excluded.root, name = excluded.name, size = excluded.size,
+                status = excluded.status, error = NULL, finished_at = NULL,
+                started_at = CASE WHEN downloads.status = 'active' AND downloads.root = excluded.root
+                                  THEN downloads.started_at ELSE excluded.started_at END",
+            params![
+                path_key(output),
+                manifest.root.to_string(),
+                manifest.name,
+                manifest.size,
+                DownloadStatus::Active.as_str(),
+                now(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records the end of a download.
+    pub fn download_finished(
+        &self,
+        output: &Path,
+        status: DownloadStatus,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE downloads SET status = ?2, error = ?3, finished_at = ?4 WHERE output = ?1",
+            params![path_key(output), status.as_str(), error, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Output paths of downloads that have not finished yet.
+    pub fn active_downloads(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT output FROM downloads WHERE status = ?1 ORDER BY started_at")?;
+        let outputs = stmt
+            .query_map(params![DownloadStatus::Active.as_str()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(outputs.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Returns the download history, most recent first.
+    pub fn downloads(&self) -> Result<Vec<DownloadRecord>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT output, root, name, size, status, error, started_at, finished_at
+             FROM downloads ORDER BY started_at DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, u64>(6)?,
+                row.get::<_, Option<u64>>(7)?,
+            ))
+        })?;
+
+        let mut records = Vec::new();
+        for row in rows {
+            let (output, root, name, size, status, error, started_at, finished_at) = row?;
+            records.push(DownloadRecord {
+                output: PathBuf::from(output),
+                root: root.parse()?,
+                name,
+                size,
+                status: DownloadStatus::parse(&status),
+                error,
+                started_at,
+                finished_at,
+            });
+        }
+        Ok(records)
+    }
+}
+
+/// Paths are stored as text; non-UTF-8 paths are stored lossily.
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn nanos_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as i64)
+}
+
+/// Current time in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    fn table_exists(conn: &Connection, table: &str) -> bool {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![table],
+            |row| row.get::<_, u32>(0),
+        )
+        .unwrap()
+            == 1
+    }
+
+    #[test]
+    fn open_creates_the_latest_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(schema_version(&store.conn), MIGRATIONS.len());
+        for table in ["downloads", "files", "peers", "peer_addresses"] {
+            assert!(table_exists(&store.conn, table), "missing table {}", table);
+        }
+        drop(store);
+
+        // Reopening an up-to-date database runs nothing
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(schema_version(&store.conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn open_migrates_an_older_database_and_keeps_its_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open(dir.path().join(DATABASE_FILE)).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO downloads (output, root, name, size, status, started_at)
+             VALUES ('/tmp/cat.jpg', ?1, 'cat.jpg', 42, 'active', 7)",
+            params![ContentHash::of(b"cat").to_string()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(schema_version(&store.conn), MIGRATIONS.len());
+        assert!(table_exists(&store.conn, "files"));
+        assert_eq!(store.active_downloads().unwrap(), vec![PathBuf::from("/tmp/cat.jpg")]);
+        let records = store.downloads().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].root, ContentHash::of(b"cat"));
+        assert_eq!(records[0].started_at, 7);
+    }
+
+    #[test]
+    fn open_refuses_a_newer_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open(dir.path().join(DATABASE_FILE)).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(conn);
+
+        assert!(Store::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn finished_downloads_are_no_longer_active() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        let manifest = Manifest {
+            name: "cat.jpg".to_string(),
+            size: 0,
+            chunk_size: 1024,
+            chunks: Vec::new(),
+            root: Manifest::root_of(0, 1024, &[]),
+        };
+        let output = Path::new("/tmp/cat.jpg");
+        store.download_started(output, &manifest).unwrap();
+        assert_eq!(store.active_downloads().unwrap(), vec![output.to_path_buf()]);
+
+        store
+            .download_finished(output, DownloadStatus::Abandoned, Some("state file missing"))
+            .unwrap();
+        assert!(store.active_downloads().unwrap().is_empty());
+        let record = &store.downloads().unwrap()[0];
+        assert_eq!(record.status, DownloadStatus::Abandoned);
+        assert_eq!(record.error.as_deref(), Some("state file missing"));
+        assert!(record.finished_at.is_some());
+    }
+}
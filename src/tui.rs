@@ -0,0 +1,839 @@
+//! Terminal interface of the `tui` command.
+//!
+//! The node runs in the same process, and the interface follows it the way
+//! the HTTP API does: its panels are kept up to date from the published
+//! [`NodeEvent`]s, and listing, searching and downloading are carried out as
+//! [`RpcCall`]s by the event loop. Transfer speeds are averaged over the last
+//! few seconds of chunks.
+
+use crate::cli::Cli;
+use crate::events::NodeEvent;
+use crate::manifest::ContentHash;
+use crate::node::{self, Frontend};
+use crate::protocol::FileEntry;
+use crate::rpc::{RpcCall, RpcError};
+use libp2p::{identity::Keypair, PeerId};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, LineGauge, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// File name of the log inside the data directory while the interface runs
+pub const LOG_FILE: &str = "tui.log";
+
+/// How often the screen is redrawn when nothing happens, so speeds and ETAs stay current
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Period transfer speeds are averaged over
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+/// How long an upload goes without a chunk request before it is shown as idle
+const UPLOAD_IDLE: Duration = Duration::from_secs(10);
+
+/// How long an upload stays in the transfers panel after its last chunk,
+/// whether it was sent completely or the peer stopped asking
+const UPLOAD_RETENTION: Duration = Duration::from_secs(60);
+
+/// How long the input thread waits for a key press before checking whether
+/// the interface is still running
+const INPUT_POLL: Duration = Duration::from_millis(100);
+
+/// Runs the node with the terminal interface until the user quits or the
+/// process receives Ctrl-C or SIGTERM.
+pub async fn run(cli: &Cli, local_key: &Keypair, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    // Subscribing before the node starts, so no event is missed
+    let (events, updates) = broadcast::channel(node::EVENT_BUFFER);
+    let (call_tx, call_rx) = unbounded_channel();
+    let (reply_tx, replies) = unbounded_channel();
+    let app = App::new(PeerId::from(local_key.public()), call_tx, reply_tx);
+    let node = node::run(cli, local_key, data_dir, Some(Frontend { calls: call_rx, events }));
+    tokio::pin!(node);
+
+    let mut terminal = ratatui::init();
+    let result = tokio::select! {
+        // The node stopped by itself, on a signal or an error
+        result = &mut node => {
+            ratatui::restore();
+            return result;
+        }
+        result = app.run(&mut terminal, updates, replies) => result,
+    };
+    ratatui::restore();
+    result?;
+    // The interface dropped its end of the calls, so the node is shutting down
+    node.await
+}
+
+/// What a call made by the interface was for
+#[derive(Debug, Clone, Copy)]
+enum Call {
+    Shares,
+    Search,
+    Download,
+    Downloads,
+    Status,
+}
+
+impl Call {
+    fn describe(self) -> &'static str {
+        match self {
+            Call::Shares => "Listing shared files",
+            Call::Search => "Search",
+            Call::Download => "Download",
+            Call::Downloads => "Download status",
+            Call::Status => "Node status",
+        }
+    }
+}
+
+/// Which part of the screen receives key presses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    SearchBox,
+    Results,
+}
+
+/// A peer of the peers panel
+#[derive(Debug, Default)]
+struct PeerRow {
+    /// Announced through mDNS and not expired
+    discovered: bool,
+    connected: bool,
+    /// Last ping round trip, while connected
+    rtt_ms: Option<u64>,
+    agent: Option<String>,
+}
+
+/// A file found by a search, as answered by the `search` call
+#[derive(Debug, Deserialize)]
+struct SearchResult {
+    peer: PeerId,
+    #[serde(flatten)]
+    entry: FileEntry,
+}
+
+/// What the interface uses of a `download_status` record
+#[derive(Debug, Deserialize)]
+struct DownloadRecord {
+    root: ContentHash,
+    name: String,
+    size: u64,
+    status: String,
+    completed_chunks: Option<u32>,
+    total_chunks: Option<u32>,
+    chunk_size: Option<u32>,
+}
+
+/// What the interface uses of a `status` answer
+#[derive(Debug, Deserialize)]
+struct NodeStatus {
+    nat_status: String,
+    public_address: Option<String>,
+}
+
+/// Answer to a `download_start` call
+#[derive(Debug, Deserialize)]
+struct DownloadStarted {
+    root: ContentHash,
+    output: PathBuf,
+}
+
+#[derive(Debug)]
+enum TransferState {
+    Active,
+    Completed,
+    Failed(String),
+}
+
+/// A download or upload of the transfers panel
+#[derive(Debug)]
+struct Transfer {
+    name: String,
+    /// Size of the file in bytes, 0 until known
+    size: u64,
+    /// Bytes transferred so far
+    done: u64,
+    state: TransferState,
+    started: Instant,
+    /// When each chunk of the last [`SPEED_WINDOW`] was transferred, and its size
+    recent: VecDeque<(Instant, u64)>,
+}
+
+impl Transfer {
+    fn new(name: String, size: u64) -> Self {
+        Transfer {
+            name,
+            size,
+            done: 0,
+            state: TransferState::Active,
+            started: Instant::now(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records a chunk of `bytes` transferred just now.
+    fn add_chunk(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.done += bytes;
+        self.recent.push_back((now, bytes));
+        while self.recent.front().is_some_and(|(at, _)| now - *at > SPEED_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Bytes per second over the last [`SPEED_WINDOW`].
+    fn speed(&self) -> f64 {
+        let now = Instant::now();
+        let period = (now - self.started).min(SPEED_WINDOW).as_secs_f64();
+        if period == 0.0 {
+            return 0.0;
+        }
+        let bytes: u64 = self
+            .recent
+            .iter()
+            .filter(|(at, _)| now - *at <= SPEED_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / period
+    }
+
+    fn ratio(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        (self.done as f64 / self.size as f64).min(1.0)
+    }
+
+    /// Time left at the current speed, if there is any progress to go by.
+    fn eta(&self) -> Option<Duration> {
+        let speed = self.speed();
+        if self.size == 0 || speed == 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(self.size.saturating_sub(self.done) as f64 / speed))
+    }
+
+    /// Whether no chunk was transferred for [`UPLOAD_IDLE`]
+    fn is_idle(&self) -> bool {
+        self.idle_for(UPLOAD_IDLE)
+    }
+
+    /// Whether no chunk was transferred for `period`.
+    fn idle_for(&self, period: Duration) -> bool {
+        self.recent.back().is_none_or(|(at, _)| at.elapsed() > period)
+    }
+}
+
+/// State of the interface
+struct App {
+    local_peer: PeerId,
+    /// Reachability detected by AutoNAT, as shown in the header
+    nat_status: String,
+    calls: UnboundedSender<RpcCall>,
+    replies: UnboundedSender<(Call, Result<Value, RpcError>)>,
+    peers: BTreeMap<PeerId, PeerRow>,
+    shares: Vec<FileEntry>,
+    /// Files were indexed or removed since the share list was fetched
+    shares_stale: bool,
+    focus: Focus,
+    query: String,
+    results: Vec<SearchResult>,
+    results_state: TableState,
+    downloads: BTreeMap<ContentHash, Transfer>,
+    uploads: BTreeMap<(PeerId, ContentHash), Transfer>,
+    /// Outcome of the last action
+    status: String,
+    quit: bool,
+}
+
+impl App {
+    fn new(
+        local_peer: PeerId,
+        calls: UnboundedSender<RpcCall>,
+        replies: UnboundedSender<(Call, Result<Value, RpcError>)>,
+    ) -> Self {
+        App {
+            local_peer,
+            nat_status: "unknown".to_string(),
+            calls,
+            replies,
+            peers: BTreeMap::new(),
+            shares: Vec::new(),
+            shares_stale: false,
+            focus: Focus::SearchBox,
+            query: String::new(),
+            results: Vec::new(),
+            results_state: TableState::default(),
+            downloads: BTreeMap::new(),
+            uploads: BTreeMap::new(),
+            status: String::new(),
+            quit: false,
+        }
+    }
+
+    /// Runs until the user quits, then drops the sending end of the calls.
+    async fn run(
+        mut self,
+        terminal: &mut DefaultTerminal,
+        mut updates: broadcast::Receiver<NodeEvent>,
+        mut replies: UnboundedReceiver<(Call, Result<Value, RpcError>)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut input = spawn_input_reader();
+        let mut refresh = tokio::time::interval(REFRESH_INTERVAL);
+        self.fetch_all();
+
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            tokio::select! {
+                Some(event) = input.recv() => {
+                    if let Event::Key(key) = event {
+                        self.on_key(key);
+                    }
+                }
+                update = updates.recv() => match update {
+                    Ok(event) => self.on_node_event(event),
+                    // Fetch again what the missed events would have changed
+                    Err(RecvError::Lagged(_)) => self.fetch_all(),
+                    Err(RecvError::Closed) => break,
+                },
+                Some((call, result)) = replies.recv() => self.on_reply(call, result),
+                _ = refresh.tick() => {
+                    self.prune_uploads();
+                    if self.shares_stale {
+                        self.shares_stale = false;
+                        self.call(Call::Shares, "files_list", Value::Null);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands a call to the event loop; its result comes back through `replies`.
+    fn call(&self, call: Call, method: &str, params: Value) {
+        let (reply, result) = oneshot::channel();
+        let rpc_call = RpcCall {
+            method: method.to_string(),
+            params,
+            reply,
+        };
+        // Fails only once the node stopped, which ends the interface too
+        if self.calls.send(rpc_call).is_err() {
+            return;
+        }
+        let replies = self.replies.clone();
+        tokio::spawn(async move {
+            let result = result.await.unwrap_or_else(|_| Err(RpcError::failed("the node stopped")));
+            let _ = replies.send((call, result));
+        });
+    }
+
+    /// Fetches the node status, the share list and the downloads in progress.
+    fn fetch_all(&self) {
+        self.call(Call::Status, "status", Value::Null);
+        self.call(Call::Shares, "files_list", Value::Null);
+        self.call(Call::Downloads, "download_status", Value::Null);
+    }
+
+    fn on_key(&mut self, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            if matches!(key.code, KeyCode::Char('c') | KeyCode::Char('q')) {
+                self.quit = true;
+            }
+            return;
+        }
+        match self.focus {
+            Focus::SearchBox => match key.code {
+                KeyCode::Char(c) => self.query.push(c),
+                KeyCode::Backspace => {
+                    self.query.pop();
+                }
+                KeyCode::Esc => self.query.clear(),
+                KeyCode::Enter => self.search(),
+                KeyCode::Tab | KeyCode::Down if !self.results.is_empty() => {
+                    self.focus = Focus::Results;
+                    if self.results_state.selected().is_none() {
+                        self.results_state.select(Some(0));
+                    }
+                }
+                _ => {}
+            },
+            Focus::Results => match key.code {
+                KeyCode::Up => self.results_state.select_previous(),
+                KeyCode::Down => self.results_state.select_next(),
+                KeyCode::Enter => self.download_selected(),
+                KeyCode::Tab | KeyCode::Esc | KeyCode::Char('/') => self.focus = Focus::SearchBox,
+                KeyCode::Char('q') => self.quit = true,
+                _ => {}
+            },
+        }
+    }
+
+    /// Searches the network for files whose name contains the query.
+    fn search(&mut self) {
+        let name = self.query.trim();
+        if name.is_empty() {
+            return;
+        }
+        self.status = format!("Searching for \"{}\"...", name);
+        self.call(Call::Search, "search", json!({ "name": name }));
+    }
+
+    /// Downloads the selected search result from the peer that reported it.
+    fn download_selected(&mut self) {
+        let Some(result) = self.results_state.selected().and_then(|i| self.results.get(i)) else {
+            return;
+        };
+        self.status = format!("Asking {} for {}", short_id(&result.peer), result.entry.name);
+        let params = json!({ "peer": result.peer.to_string(), "file": result.entry.root.to_string() });
+        self.call(Call::Download, "download_start", params);
+    }
+
+    fn on_node_event(&mut self, event: NodeEvent) {
+        match event {
+            NodeEvent::PeerDiscovered { peer, .. } => self.peers.entry(peer).or_default().discovered = true,
+            NodeEvent::PeerExpired { peer, .. } => {
+                if let Some(row) = self.peers.get_mut(&peer) {
+                    row.discovered = false;
+                }
+                self.forget_if_gone(&peer);
+            }
+            NodeEvent::PeerConnected { peer, .. } => self.peers.entry(peer).or_default().connected = true,
+            NodeEvent::PeerDisconnected { peer } => {
+                if let Some(row) = self.peers.get_mut(&peer) {
+                    row.connected = false;
+                    row.rtt_ms = None;
+                }
+                self.forget_if_gone(&peer);
+            }
+            NodeEvent::PeerIdentified { peer, agent_version, .. } => {
+                self.peers.entry(peer).or_default().agent = Some(agent_version);
+            }
+            NodeEvent::NatStatus { status, address } => {
+                self.nat_status = describe_nat_status(status, address.map(|address| address.to_string()));
+            }
+            NodeEvent::Ping { peer, rtt_ms } => {
+                if let Some(row) = self.peers.get_mut(&peer) {
+                    row.rtt_ms = Some(rtt_ms);
+                }
+            }
+            NodeEvent::FileIndexed { .. } | NodeEvent::FileRemoved { .. } => self.shares_stale = true,
+            NodeEvent::ChunkServed { peer, root, bytes } => {
+                let (name, size) = self
+                    .shares
+                    .iter()
+                    .find(|entry| entry.root == root)
+                    .map_or((root.to_string(), 0), |entry| (entry.name.clone(), entry.size));
+                self.uploads
+                    .entry((peer, root))
+                    .or_insert_with(|| Transfer::new(name, size))
+                    .add_chunk(bytes);
+            }
+            NodeEvent::DownloadProgress { root, bytes, .. } => match self.downloads.get_mut(&root) {
+                Some(transfer) => transfer.add_chunk(bytes),
+                // Started by another tool; its name and size are fetched
+                None => {
+                    let mut transfer = Transfer::new(root.to_string(), 0);
+                    transfer.add_chunk(bytes);
+                    self.downloads.insert(root, transfer);
+                    self.call(Call::Downloads, "download_status", json!({ "root": root }));
+                }
+            },
+            NodeEvent::DownloadCompleted { root, .. } => {
+                if let Some(transfer) = self.downloads.get_mut(&root) {
+                    transfer.done = transfer.size;
+                    transfer.state = TransferState::Completed;
+                }
+            }
+            NodeEvent::DownloadFailed { root, error } => {
+                if let Some(transfer) = self.downloads.get_mut(&root) {
+                    transfer.state = TransferState::Failed(error);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes the uploads that received no chunk request for [`UPLOAD_RETENTION`].
+    fn prune_uploads(&mut self) {
+        self.uploads.retain(|_, transfer| !transfer.idle_for(UPLOAD_RETENTION));
+    }
+
+    /// Removes a peer that is neither connected nor announced anymore.
+    fn forget_if_gone(&mut self, peer: &PeerId) {
+        if self.peers.get(peer).is_some_and(|row| !row.connected && !row.discovered) {
+            self.peers.remove(peer);
+        }
+    }
+
+    fn on_reply(&mut self, call: Call, result: Result<Value, RpcError>) {
+        let value = match result {
+            Ok(value) => value,
+            Err(e) => {
+                self.status = format!("{} failed: {}", call.describe(), e.message);
+                return;
+            }
+        };
+        match call {
+            Call::Shares => {
+                if let Some(mut shares) = self.parse::<Vec<FileEntry>>(value) {
+                    shares.sort_by(|a, b| a.name.cmp(&b.name));
+                    self.shares = shares;
+                }
+            }
+            Call::Search => {
+                if let Some(results) = self.parse::<Vec<SearchResult>>(value) {
+                    let noun = if results.len() == 1 { "result" } else { "results" };
+                    self.status = format!("{} {} for \"{}\"", results.len(), noun, self.query.trim());
+                    self.results_state.select((!results.is_empty()).then_some(0));
+                    self.results = results;
+                }
+            }
+            Call::Download => {
+                if let Some(started) = self.parse::<DownloadStarted>(value) {
+                    let name = started
+                        .output
+                        .file_name()
+                        .map_or(started.root.to_string(), |name| name.to_string_lossy().into_owned());
+                    self.downloads
+                        .entry(started.root)
+                        .or_insert_with(|| Transfer::new(name, 0));
+                    self.status = format!("Downloading to {}", started.output.display());
+                    self.call(Call::Downloads, "download_status", json!({ "root": started.root }));
+                }
+            }
+            Call::Status => {
+                if let Some(status) = self.parse::<NodeStatus>(value) {
+                    self.nat_status = describe_nat_status(&status.nat_status, status.public_address);
+                }
+            }
+            Call::Downloads => {
+                for record in self.parse::<Vec<DownloadRecord>>(value).into_iter().flatten() {
+                    self.merge_download(record);
+                }
+            }
+        }
+    }
+
+    /// Parses the result of a call, reporting results the interface does not understand.
+    fn parse<T: DeserializeOwned>(&mut self, value: Value) -> Option<T> {
+        serde_json::from_value(value)
+            .map_err(|e| self.status = format!("Unexpected answer from the node: {}", e))
+            .ok()
+    }
+
+    /// Completes a download's name and size from its record; downloads in
+    /// progress that are not shown yet are added.
+    fn merge_download(&mut self, record: DownloadRecord) {
+        if record.status != "active" && !self.downloads.contains_key(&record.root) {
+            return;
+        }
+        let done = match (record.completed_chunks, record.total_chunks, record.chunk_size) {
+            (Some(completed), Some(total), _) if completed == total => record.size,
+            (Some(completed), _, Some(chunk_size)) => (u64::from(completed) * u64::from(chunk_size)).min(record.size),
+            _ => 0,
+        };
+        let transfer = self
+            .downloads
+            .entry(record.root)
+            .or_insert_with(|| Transfer::new(record.name.clone(), record.size));
+        transfer.name = record.name;
+        transfer.size = record.size;
+        transfer.done = transfer.done.max(done);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, top, search, results, transfers, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Percentage(30),
+            Constraint::Length(3),
+            Constraint::Percentage(25),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [peers, shares] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(top);
+
+        self.draw_header(frame, header);
+        self.draw_peers(frame, peers);
+        self.draw_shares(frame, shares);
+        self.draw_search_box(frame, search);
+        self.draw_results(frame, results);
+        self.draw_transfers(frame, transfers);
+        self.draw_footer(frame, footer);
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let nat = format!("NAT: {}", self.nat_status);
+        let [left, right] = Layout::horizontal([Constraint::Fill(1), Constraint::Length(nat.chars().count() as u16)])
+            .spacing(2)
+            .areas(area);
+        frame.render_widget(Paragraph::new(self.local_peer.to_string()).style(header_style()), left);
+        frame.render_widget(Paragraph::new(nat), right);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame, area: Rect) {
+        let connected = self.peers.values().filter(|row| row.connected).count();
+        let rows = self.peers.iter().map(|(peer, row)| {
+            Row::new([
+                short_id(peer),
+                if row.connected { "connected" } else { "discovered" }.to_string(),
+                row.rtt_ms.map_or("-".to_string(), |rtt| format!("{} ms", rtt)),
+                row.agent.clone().unwrap_or_default(),
+            ])
+        });
+        let widths = [
+            Constraint::Length(12),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Fill(1),
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(["Peer", "State", "RTT", "Agent"]).style(header_style()))
+            .block(Block::bordered().title(format!(" Peers ({} connected) ", connected)));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_shares(&self, frame: &mut Frame, area: Rect) {
+        let rows = self
+            .shares
+            .iter()
+            .map(|entry| Row::new([entry.name.clone(), format_bytes(entry.size as f64)]));
+        let table = Table::new(rows, [Constraint::Fill(1), Constraint::Length(10)])
+            .header(Row::new(["Name", "Size"]).style(header_style()))
+            .block(Block::bordered().title(format!(" Shared files ({}) ", self.shares.len())));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_search_box(&self, frame: &mut Frame, area: Rect) {
+        let focused = self.focus == Focus::SearchBox;
+        let block = Block::bordered()
+            .title(" Search ")
+            .border_style(focus_style(focused));
+        frame.render_widget(Paragraph::new(self.query.as_str()).block(block), area);
+        if focused {
+            let offset = self.query.chars().count().min(area.width.saturating_sub(3) as usize) as u16;
+            frame.set_cursor_position((area.x + 1 + offset, area.y + 1));
+        }
+    }
+
+    fn draw_results(&mut self, frame: &mut Frame, area: Rect) {
+        let rows = self.results.iter().map(|result| {
+            Row::new([
+                result.entry.name.clone(),
+                format_bytes(result.entry.size as f64),
+                short_id(&result.peer),
+            ])
+        });
+        let widths = [Constraint::Fill(1), Constraint::Length(10), Constraint::Length(12)];
+        let block = Block::bordered()
+            .title(" Results ")
+            .border_style(focus_style(self.focus == Focus::Results));
+        let table = Table::new(rows, widths)
+            .header(Row::new(["Name", "Size", "Peer"]).style(header_style()))
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .block(block);
+        frame.render_stateful_widget(table, area, &mut self.results_state);
+    }
+
+    fn draw_transfers(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Transfers ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        if self.downloads.is_empty() && self.uploads.is_empty() {
+            let empty = Paragraph::new("No transfers").style(Style::default().fg(Color::DarkGray));
+            frame.render_widget(empty, inner);
+            return;
+        }
+
+        let downloads = self
+            .downloads
+            .values()
+            .map(|transfer| (format!("↓ {}", transfer.name), transfer, false));
+        let uploads = self.uploads.iter().map(|((peer, _), transfer)| {
+            (format!("↑ {} to {}", transfer.name, short_id(peer)), transfer, true)
+        });
+        for (row, (label, transfer, is_upload)) in inner.rows().zip(downloads.chain(uploads)) {
+            let [label_area, gauge_area, stats_area] = Layout::horizontal([
+                Constraint::Percentage(35),
+                Constraint::Fill(1),
+                Constraint::Length(24),
+            ])
+            .spacing(1)
+            .areas(row);
+            let stats = match &transfer.state {
+                TransferState::Completed => "done".to_string(),
+                TransferState::Failed(error) => format!("failed: {}", error),
+                TransferState::Active if is_upload && transfer.size > 0 && transfer.done >= transfer.size => {
+                    "sent".to_string()
+                }
+                TransferState::Active if is_upload && transfer.is_idle() => "idle".to_string(),
+                TransferState::Active => format!(
+                    "{}/s  ETA {}",
+                    format_bytes(transfer.speed()),
+                    transfer.eta().map_or("-".to_string(), format_duration)
+                ),
+            };
+            let color = match transfer.state {
+                TransferState::Completed => Color::Green,
+                TransferState::Failed(_) => Color::Red,
+                TransferState::Active => Color::Cyan,
+            };
+            let gauge = LineGauge::default()
+                .ratio(transfer.ratio())
+                .label(format!("{:>3}%", (transfer.ratio() * 100.0) as u32))
+                .filled_style(Style::default().fg(color));
+            frame.render_widget(Paragraph::new(label), label_area);
+            frame.render_widget(gauge, gauge_area);
+            frame.render_widget(Paragraph::new(stats), stats_area);
+        }
+    }
+
+    fn draw_footer(&self, frame: &mut Frame, area: Rect) {
+        let keys = match self.focus {
+            Focus::SearchBox => "Enter search · Tab results · Ctrl-C quit",
+            Focus::Results => "↑↓ select · Enter download · Tab search · q quit",
+        };
+        let help = format!("{}  {}", keys, self.status);
+        frame.render_widget(Paragraph::new(help).style(Style::default().fg(Color::DarkGray)), area);
+    }
+}
+
+/// Reads terminal events on a thread of its own, until the receiver is dropped.
+fn spawn_input_reader() -> UnboundedReceiver<Event> {
+    let (tx, rx) = unbounded_channel();
+    std::thread::spawn(move || {
+        while !tx.is_closed() {
+            match event::poll(INPUT_POLL) {
+                Ok(true) => match event::read() {
+                    Ok(event) => {
+                        let _ = tx.send(event);
+                    }
+                    Err(_) => break,
+                },
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+/// The NAT status as shown in the header, with the public address when there is one.
+fn describe_nat_status(status: &str, public_address: Option<String>) -> String {
+    match public_address {
+        Some(address) => format!("{} ({})", status, address),
+        None => status.to_string(),
+    }
+}
+
+fn header_style() -> Style {
+    Style::default().add_modifier(Modifier::BOLD)
+}
+
+fn focus_style(focused: bool) -> Style {
+    if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    }
+}
+
+/// The end of a peer id, which tells peers apart unlike its common prefix
+fn short_id(peer: &PeerId) -> String {
+    let id = peer.to_string();
+    format!("…{}", &id[id.len().saturating_sub(10)..])
+}
+
+/// Formats a number of bytes with a binary unit, e.g. `1.5 MiB`.
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", value as u64)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration as `m:ss`, or `h:mm:ss` from an hour.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        App::new(PeerId::random(), unbounded_channel().0, unbounded_channel().0)
+    }
+
+    fn record(root: ContentHash, completed: u32, chunk_size: u32) -> DownloadRecord {
+        DownloadRecord {
+            root,
+            name: "file.bin".to_string(),
+            size: 10 * u64::from(chunk_size),
+            status: "active".to_string(),
+            completed_chunks: Some(completed),
+            total_chunks: Some(10),
+            chunk_size: Some(chunk_size),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn uploads_are_removed_once_the_peer_stops_asking() {
+        let mut app = app();
+        let root = ContentHash::of(b"shared");
+        app.on_node_event(NodeEvent::ChunkServed { peer: PeerId::random(), root, bytes: 1024 });
+        app.prune_uploads();
+        assert_eq!(app.uploads.len(), 1);
+
+        tokio::time::advance(UPLOAD_RETENTION / 2).await;
+        app.on_node_event(NodeEvent::ChunkServed { peer: PeerId::random(), root, bytes: 1024 });
+        tokio::time::advance(UPLOAD_RETENTION / 2 + Duration::from_secs(1)).await;
+        app.prune_uploads();
+        assert_eq!(app.uploads.len(), 1, "only the upload idle for the whole period is removed");
+
+        tokio::time::advance(UPLOAD_RETENTION).await;
+        app.prune_uploads();
+        assert!(app.uploads.is_empty());
+    }
+
+    #[test]
+    fn download_progress_uses_the_manifest_chunk_size() {
+        let mut app = app();
+        let root = ContentHash::of(b"downloaded");
+        app.merge_download(record(root, 3, 4096));
+        assert_eq!(app.downloads[&root].done, 3 * 4096);
+        assert_eq!(app.downloads[&root].size, 10 * 4096);
+
+        app.merge_download(record(root, 10, 4096));
+        assert_eq!(app.downloads[&root].done, 10 * 4096);
+    }
+}